@@ -1,11 +1,17 @@
 use anyhow::{anyhow, Result};
-use prolink_nfs::NfsClient;
+use log::warn;
+use prolink::{database::Database, dump::LibraryDump};
+use prolink_nfs::{FileType, NfsClient};
 use std::{
+    io::{BufWriter, Write},
     net::{IpAddr, ToSocketAddrs},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use structopt::StructOpt;
-use tokio::{fs::File, io::AsyncWriteExt};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncSeek, AsyncWriteExt},
+};
 
 #[derive(StructOpt)]
 #[structopt(about = "prolink nfs utility")]
@@ -13,11 +19,34 @@ enum Opt {
     Ls {
         paths: Vec<String>,
     },
+    #[structopt(about = "List a host's registered RPC programs and check that each answers")]
+    Probe {
+        host: String,
+    },
     Get {
         remote_path: String,
         #[structopt(parse(from_os_str))]
         local_path: PathBuf,
     },
+    #[structopt(about = "Export a rekordbox database as JSON and/or CSV")]
+    Dump {
+        #[structopt(help = "export.pdb as a local file or host:path")]
+        database: String,
+        #[structopt(long, parse(from_os_str), help = "File to write JSON to")]
+        json: Option<PathBuf>,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "Directory to write tracks.csv and playlists.csv to"
+        )]
+        csv: Option<PathBuf>,
+    },
+}
+
+fn lookup_host(host: &str) -> Result<IpAddr> {
+    let mut addrs = (host, 0).to_socket_addrs()?;
+    let addr = addrs.next().ok_or(anyhow!("filed to lookup {}", host))?;
+    Ok(addr.ip())
 }
 
 fn parse_nfs_path(nfs_path: &str) -> Result<(IpAddr, String)> {
@@ -25,9 +54,7 @@ fn parse_nfs_path(nfs_path: &str) -> Result<(IpAddr, String)> {
         .split_once(":")
         .ok_or(anyhow!("No : in path spec {}", nfs_path))?;
 
-    let mut addrs = (host, 0).to_socket_addrs()?;
-    let addr = addrs.next().ok_or(anyhow!("filed to lookup {}", host))?;
-    Ok((addr.ip(), path.to_string()))
+    Ok((lookup_host(host)?, path.to_string()))
 }
 
 async fn ls(paths: &Vec<String>) -> Result<()> {
@@ -43,10 +70,47 @@ async fn ls(paths: &Vec<String>) -> Result<()> {
             println!("  {}", export);
         }
 
-        let files = client.list_files(&path).await?;
+        let entries = client.list_dir(&path).await?;
         println!("Files");
-        for file in files {
-            println!("  {}", file);
+        for entry in entries {
+            let suffix = match entry.file_type {
+                FileType::Directory => "/",
+                _ => "",
+            };
+            println!("  {:>12} {}{}", entry.size, entry.name, suffix);
+        }
+    }
+    Ok(())
+}
+
+async fn probe(host: &str) -> Result<()> {
+    let probe = NfsClient::probe(lookup_host(host)?).await?;
+    println!("portmapper answered in {:?}", probe.portmap_latency);
+
+    println!("Programs");
+    for program in &probe.programs {
+        println!(
+            "  {:>8} v{} {:<4} {:>5} {}",
+            program.program,
+            program.version,
+            program.protocol_name().unwrap_or("?"),
+            program.port,
+            program.name().unwrap_or("")
+        );
+    }
+
+    println!("Pings");
+    for ping in &probe.pings {
+        let name = ping.program.name().unwrap_or("?");
+        match &ping.latency {
+            Ok(latency) => println!(
+                "  {} v{} on {}: {:?}",
+                name, ping.program.version, ping.program.port, latency
+            ),
+            Err(e) => println!(
+                "  {} v{} on {}: {}",
+                name, ping.program.version, ping.program.port, e
+            ),
         }
     }
     Ok(())
@@ -55,12 +119,68 @@ async fn ls(paths: &Vec<String>) -> Result<()> {
 async fn get(remote_path: &str, local_path: &PathBuf) -> Result<()> {
     let (addr, path) = parse_nfs_path(remote_path)?;
     let mut client = NfsClient::connect(addr).await?;
-    let data = client.get_file(&path).await?;
+    let mut r = client.open(&path).await?;
 
     let mut w = File::create(local_path).await?;
-    w.write_all(&data).await?;
+    tokio::io::copy(&mut r, &mut w).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+// Picks up My Tags from the exportExt.pdb next to an export.pdb.  A damaged
+// one only loses the tags, like it does for `MetadataTask`.
+async fn parse_ext<R: AsyncRead + AsyncSeek + Unpin>(db: &mut Database, r: &mut R, path: &str) {
+    if let Err(e) = db.parse_ext(r).await {
+        warn!("Failed to parse ext database at {}: {}", path, e);
+    }
+}
+
+async fn load_database(database: &str) -> Result<Database> {
+    if Path::new(database).exists() {
+        let mut db = Database::parse(&mut File::open(database).await?).await?;
+
+        let ext_path = Path::new(database).with_file_name("exportExt.pdb");
+        if let Ok(mut file) = File::open(&ext_path).await {
+            parse_ext(&mut db, &mut file, &ext_path.to_string_lossy()).await;
+        }
+        return Ok(db);
+    }
+
+    let (addr, path) = parse_nfs_path(database)?;
+    let mut client = NfsClient::connect(addr).await?;
+    let mut db = Database::parse(&mut client.open(&path).await?).await?;
+
+    if let Some((dir, _)) = path.rsplit_once("/") {
+        let ext_path = dir.to_owned() + "/exportExt.pdb";
+        if let Ok(mut file) = client.open(&ext_path).await {
+            parse_ext(&mut db, &mut file, &ext_path).await;
+        }
+    }
+    Ok(db)
+}
+
+async fn dump(database: &str, json: &Option<PathBuf>, csv: &Option<PathBuf>) -> Result<()> {
+    if json.is_none() && csv.is_none() {
+        return Err(anyhow!("Nothing to do, specify --json and/or --csv"));
+    }
+
+    let db = load_database(database).await?;
+    let dump = LibraryDump::new(&db);
+
+    if let Some(path) = json {
+        let mut w = BufWriter::new(std::fs::File::create(path)?);
+        dump.write_json(&mut w)?;
+        w.flush()?;
+    }
+
+    if let Some(dir) = csv {
+        std::fs::create_dir_all(dir)?;
+        dump.write_tracks_csv(std::fs::File::create(dir.join("tracks.csv"))?)?;
+        dump.write_playlists_csv(std::fs::File::create(dir.join("playlists.csv"))?)?;
+    }
     Ok(())
 }
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -69,9 +189,15 @@ async fn main() -> Result<()> {
 
     match opt {
         Opt::Ls { paths } => ls(&paths).await,
+        Opt::Probe { host } => probe(&host).await,
         Opt::Get {
             remote_path,
             local_path,
         } => get(&remote_path, &local_path).await,
+        Opt::Dump {
+            database,
+            json,
+            csv,
+        } => dump(&database, &json, &csv).await,
     }
 }
@@ -0,0 +1,51 @@
+// Compares reading a file one `READ` at a time with pipelined `READ`s.
+//
+//     cargo run --example read_speed -- <ip> <path>
+//
+// For example `<ip> /C/PIONEER/rekordbox/export.pdb` on a player with a USB
+// stick in it.  `bench_pipelined_read` in the crate's tests measures the same
+// against a local mock server.
+
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use prolink_nfs::{NfsClient, Result, RpcConfig};
+
+async fn timed_read(ip: IpAddr, path: &str, window: usize) -> Result<(usize, Duration)> {
+    let config = RpcConfig {
+        window,
+        ..RpcConfig::default()
+    };
+    let mut client = NfsClient::connect_with_config(ip, &config).await?;
+
+    let start = Instant::now();
+    let data = client.get_file(path).await?;
+    Ok((data.len(), start.elapsed()))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <ip> <path>", args[0]);
+        std::process::exit(1);
+    }
+    let ip: IpAddr = args[1]
+        .parse()
+        .map_err(|_| prolink_nfs::Error::Address(args[1].clone()))?;
+
+    for window in [1, 8] {
+        let (len, elapsed) = timed_read(ip, &args[2], window).await?;
+        println!(
+            "window {}: {} bytes in {:?}, {:.0} KiB/s",
+            window,
+            len,
+            elapsed,
+            len as f64 / 1024.0 / elapsed.as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use std::net::{IpAddr, SocketAddr};
 
-use super::rpc::{NoneParam, Rpc};
-use crate::Result;
+use super::rpc::{NoneParam, Rpc, RpcConfig, Transport};
+use crate::{Error, Result};
 
 #[allow(
     dead_code,
@@ -16,12 +16,12 @@ pub(super) mod xdr {
     include!(concat!(env!("OUT_DIR"), "/bind_xdr.rs"));
 }
 
-const RPCBPROG: u32 = 100000;
-const RPCBVERS: u32 = 2;
+pub(super) const RPCBPROG: u32 = 100000;
+pub(super) const RPCBVERS: u32 = 2;
 
 #[repr(u32)]
 #[allow(dead_code)]
-enum RpcbProg {
+pub(super) enum RpcbProg {
     NULL = 0,
     SET = 1,
     UNSET = 2,
@@ -32,12 +32,13 @@ enum RpcbProg {
 
 #[repr(u32)]
 #[derive(Clone, Copy, Debug)]
-#[allow(dead_code)]
 pub(super) enum Protocol {
     TCP = xdr::IPPROTO_TCP as u32,
     UDP = xdr::IPPROTO_UDP as u32,
 }
 
+pub(super) const PMAP_PORT: u16 = xdr::PMAP_PORT as u16;
+
 pub(super) type Mapping = xdr::mapping;
 
 pub(super) struct Bind {
@@ -45,8 +46,8 @@ pub(super) struct Bind {
 }
 
 impl Bind {
-    pub async fn connect(ip: IpAddr) -> Result<Bind> {
-        let rpc = Rpc::connect(SocketAddr::new(ip, xdr::PMAP_PORT as u16)).await?;
+    pub async fn connect(addr: SocketAddr, config: &RpcConfig) -> Result<Bind> {
+        let rpc = Rpc::connect(addr, config).await?;
         Ok(Bind { rpc })
     }
 
@@ -69,7 +70,35 @@ impl Bind {
         Ok(port as u16)
     }
 
-    #[allow(dead_code)]
+    // Connects to `prog` over the transport `config` asks for.  With
+    // `Transport::Auto`, TCP is tried first.
+    pub async fn connect_program(
+        &mut self,
+        ip: IpAddr,
+        prog: u32,
+        vers: u32,
+        config: &RpcConfig,
+    ) -> Result<Rpc> {
+        if config.transport != Transport::Udp {
+            let res = match self.lookup(prog, vers, Protocol::TCP).await? {
+                0 => Err(Error::NotRegistered { prog, vers }),
+                port => Rpc::connect_tcp(SocketAddr::new(ip, port), config).await,
+            };
+            if config.transport == Transport::Tcp || res.is_ok() {
+                return res;
+            }
+        }
+
+        match self.lookup(prog, vers, Protocol::UDP).await? {
+            0 => Err(Error::NotRegistered { prog, vers }),
+            port => Rpc::connect(SocketAddr::new(ip, port), config).await,
+        }
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.rpc.null(RPCBPROG, RPCBVERS).await
+    }
+
     pub async fn list(&mut self) -> Result<Vec<Mapping>> {
         let mut entry: xdr::pmaplist_ptr = self
             .rpc
@@ -93,3 +122,76 @@ impl Bind {
         Ok(mappings)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, net::Ipv4Addr};
+    use tokio::net::TcpListener;
+    use xdr_codec::Pack;
+
+    use super::super::mock::{self, Reply};
+    use super::*;
+
+    const PROG: u32 = 200000;
+    const VERS: u32 = 1;
+
+    // Serves a portmapper that maps `PROG` to `tcp_port` and `udp_port`.
+    async fn serve_portmap(tcp_port: u16, udp_port: u16) -> SocketAddr {
+        mock::serve(move |call, args| {
+            assert_eq!(call.proc_, RpcbProg::GETPORT as u32);
+            let mapping: Mapping = xdr_codec::unpack(args).unwrap();
+            let port = if (mapping.prog, mapping.vers) != (PROG, VERS) {
+                0
+            } else if mapping.prot == Protocol::TCP as u32 {
+                tcp_port as u32
+            } else {
+                udp_port as u32
+            };
+            let mut res = Cursor::new(Vec::new());
+            port.pack(&mut res).unwrap();
+            Reply::Send(res.into_inner())
+        })
+        .await
+    }
+
+    async fn connect(portmap: SocketAddr, transport: Transport) -> Result<Rpc> {
+        let config = RpcConfig {
+            transport,
+            ..RpcConfig::default()
+        };
+        let mut bind = Bind::connect(portmap, &config).await.unwrap();
+        bind.connect_program(IpAddr::V4(Ipv4Addr::LOCALHOST), PROG, VERS, &config)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_connect_program() {
+        let tcp = mock::serve_tcp(|_, _| Reply::Send(Vec::new())).await;
+        let udp = mock::serve(|_, _| Reply::Send(Vec::new())).await;
+
+        let portmap = serve_portmap(tcp.port(), udp.port()).await;
+        assert!(!connect(portmap, Transport::Udp).await.unwrap().is_tcp());
+        assert!(connect(portmap, Transport::Tcp).await.unwrap().is_tcp());
+        let mut rpc = connect(portmap, Transport::Auto).await.unwrap();
+        assert!(rpc.is_tcp());
+        rpc.null(PROG, VERS).await.unwrap();
+
+        // Without a TCP registration `Auto` falls back to UDP.
+        let portmap = serve_portmap(0, udp.port()).await;
+        assert!(matches!(
+            connect(portmap, Transport::Tcp).await,
+            Err(Error::NotRegistered { prog: PROG, .. })
+        ));
+        let mut rpc = connect(portmap, Transport::Auto).await.unwrap();
+        assert!(!rpc.is_tcp());
+        rpc.null(PROG, VERS).await.unwrap();
+
+        // As it does when the TCP service refuses connections.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let portmap = serve_portmap(closed_port, udp.port()).await;
+        assert!(connect(portmap, Transport::Tcp).await.is_err());
+        assert!(!connect(portmap, Transport::Auto).await.unwrap().is_tcp());
+    }
+}
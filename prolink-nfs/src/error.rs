@@ -0,0 +1,242 @@
+use std::fmt;
+use thiserror::Error;
+use xdr_codec::{Pack, Unpack};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    // An NFS procedure failed.
+    #[error("{context}: {status}")]
+    Nfs { context: String, status: NfsStatus },
+
+    #[error("mount of {path} failed: {status}")]
+    Mount { path: String, status: NfsStatus },
+
+    // The server accepted the call but couldn't run it.
+    #[error("RPC call not accepted: {0:?}")]
+    NotAccepted(AcceptStatus),
+
+    #[error("RPC call rejected: {0:?}")]
+    Rejected(RejectStatus),
+
+    #[error(
+        "timed out waiting for reply to program {prog} procedure {proc} after {retries} retries"
+    )]
+    Timeout { prog: u32, proc: u32, retries: u32 },
+
+    #[error("error encoding {0}")]
+    Encode(String),
+
+    #[error("error decoding {0}")]
+    Decode(String),
+
+    #[error("can't find export mount for {0}")]
+    NoExport(String),
+
+    #[error("program {prog} version {vers} isn't registered with the portmapper")]
+    NotRegistered { prog: u32, vers: u32 },
+
+    #[error("can't resolve address {0}")]
+    Address(String),
+
+    #[error("offset {0} out of range for NFSv2")]
+    OffsetOutOfRange(u64),
+
+    #[error("incomplete write")]
+    IncompleteWrite,
+
+    // The file ended before the size the server reported for it.
+    #[error("file ended after {actual} of {expected} bytes")]
+    ShortRead { expected: u64, actual: u64 },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    // The status of a failed NFS or mount procedure.
+    pub fn status(&self) -> Option<NfsStatus> {
+        match self {
+            Error::Nfs { status, .. } | Error::Mount { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Why an accepted RPC call wasn't run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AcceptStatus {
+    ProgUnavail,
+    ProgMismatch { low: u32, high: u32 },
+    ProcUnavail,
+    GarbageArgs,
+    SystemErr,
+    Unknown(u32),
+}
+
+impl AcceptStatus {
+    pub(crate) fn from_u32(status: u32) -> AcceptStatus {
+        match status {
+            1 => AcceptStatus::ProgUnavail,
+            3 => AcceptStatus::ProcUnavail,
+            4 => AcceptStatus::GarbageArgs,
+            5 => AcceptStatus::SystemErr,
+            _ => AcceptStatus::Unknown(status),
+        }
+    }
+
+    pub(crate) fn to_u32(self) -> u32 {
+        match self {
+            AcceptStatus::ProgUnavail => 1,
+            AcceptStatus::ProgMismatch { .. } => 2,
+            AcceptStatus::ProcUnavail => 3,
+            AcceptStatus::GarbageArgs => 4,
+            AcceptStatus::SystemErr => 5,
+            AcceptStatus::Unknown(status) => status,
+        }
+    }
+}
+
+// Why an RPC call was rejected.  Authentication errors carry the RPC
+// `auth_stat`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RejectStatus {
+    RpcMismatch { low: u32, high: u32 },
+    AuthError(u32),
+}
+
+// A failure status returned by an NFS or mount procedure.  The values are
+// UNIX error numbers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NfsStatus {
+    Perm,
+    NoEnt,
+    Io,
+    NxIo,
+    Access,
+    Exist,
+    NoDev,
+    NotDir,
+    IsDir,
+    FBig,
+    NoSpc,
+    RoFs,
+    NameTooLong,
+    NotEmpty,
+    DQuot,
+    Stale,
+    WFlush,
+    Unknown(u32),
+}
+
+impl NfsStatus {
+    pub fn from_u32(status: u32) -> NfsStatus {
+        match status {
+            1 => NfsStatus::Perm,
+            2 => NfsStatus::NoEnt,
+            5 => NfsStatus::Io,
+            6 => NfsStatus::NxIo,
+            13 => NfsStatus::Access,
+            17 => NfsStatus::Exist,
+            19 => NfsStatus::NoDev,
+            20 => NfsStatus::NotDir,
+            21 => NfsStatus::IsDir,
+            27 => NfsStatus::FBig,
+            28 => NfsStatus::NoSpc,
+            30 => NfsStatus::RoFs,
+            63 => NfsStatus::NameTooLong,
+            66 => NfsStatus::NotEmpty,
+            69 => NfsStatus::DQuot,
+            70 => NfsStatus::Stale,
+            99 => NfsStatus::WFlush,
+            _ => NfsStatus::Unknown(status),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            NfsStatus::Perm => 1,
+            NfsStatus::NoEnt => 2,
+            NfsStatus::Io => 5,
+            NfsStatus::NxIo => 6,
+            NfsStatus::Access => 13,
+            NfsStatus::Exist => 17,
+            NfsStatus::NoDev => 19,
+            NfsStatus::NotDir => 20,
+            NfsStatus::IsDir => 21,
+            NfsStatus::FBig => 27,
+            NfsStatus::NoSpc => 28,
+            NfsStatus::RoFs => 30,
+            NfsStatus::NameTooLong => 63,
+            NfsStatus::NotEmpty => 66,
+            NfsStatus::DQuot => 69,
+            NfsStatus::Stale => 70,
+            NfsStatus::WFlush => 99,
+            NfsStatus::Unknown(status) => status,
+        }
+    }
+}
+
+impl fmt::Display for NfsStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            NfsStatus::Perm => "not owner",
+            NfsStatus::NoEnt => "no such file or directory",
+            NfsStatus::Io => "I/O error",
+            NfsStatus::NxIo => "no such device or address",
+            NfsStatus::Access => "permission denied",
+            NfsStatus::Exist => "file exists",
+            NfsStatus::NoDev => "no such device",
+            NfsStatus::NotDir => "not a directory",
+            NfsStatus::IsDir => "is a directory",
+            NfsStatus::FBig => "file too large",
+            NfsStatus::NoSpc => "no space left on device",
+            NfsStatus::RoFs => "read-only filesystem",
+            NfsStatus::NameTooLong => "file name too long",
+            NfsStatus::NotEmpty => "directory not empty",
+            NfsStatus::DQuot => "disk quota exceeded",
+            NfsStatus::Stale => "stale file handle",
+            NfsStatus::WFlush => "write cache flushed",
+            NfsStatus::Unknown(status) => return write!(f, "unknown status {}", status),
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl std::error::Error for NfsStatus {}
+
+// A procedure result that is a status followed by `T` when the status is
+// zero.  Unlike matching on the generated union types, decoding this keeps
+// the status of failed calls.  The server encodes its results with it too.
+pub(crate) struct StatusResult<T>(pub std::result::Result<T, NfsStatus>);
+
+impl<T> StatusResult<T> {
+    // Converts a failed status to an `Error::Nfs` described by `context`.
+    pub fn context<F: FnOnce() -> String>(self, context: F) -> Result<T> {
+        self.0.map_err(|status| Error::Nfs {
+            context: context(),
+            status,
+        })
+    }
+}
+
+impl<In: xdr_codec::Read, T: Unpack<In>> Unpack<In> for StatusResult<T> {
+    fn unpack(input: &mut In) -> xdr_codec::Result<(Self, usize)> {
+        let (status, size) = u32::unpack(input)?;
+        if status != 0 {
+            return Ok((StatusResult(Err(NfsStatus::from_u32(status))), size));
+        }
+        let (body, body_size) = T::unpack(input)?;
+        Ok((StatusResult(Ok(body)), size + body_size))
+    }
+}
+
+impl<Out: xdr_codec::Write, T: Pack<Out>> Pack<Out> for StatusResult<T> {
+    fn pack(&self, out: &mut Out) -> xdr_codec::Result<usize> {
+        match &self.0 {
+            Ok(body) => Ok(0u32.pack(out)? + body.pack(out)?),
+            Err(status) => status.to_u32().pack(out),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    convert::TryFrom,
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    sync::Mutex,
+    task::JoinHandle,
+};
+
+use super::nfs::Nfs;
+use super::FileHandle;
+use crate::{Error, Result};
+
+// How much data each read ahead fetches.
+pub const DEFAULT_READ_AHEAD: usize = 64 * 1024;
+
+// A file on an NFS server.  Data is fetched with `READ`s as it's read.  Once
+// a read is served, the following `read_ahead` bytes are fetched in the
+// background so sequential readers rarely wait on the network.
+pub struct NfsFile {
+    nfs: Arc<Mutex<Nfs>>,
+    handle: FileHandle,
+    size: u64,
+    pos: u64,
+    read_ahead: usize,
+
+    // File data starting at `buf_offset`.
+    buf: Vec<u8>,
+    buf_offset: u64,
+
+    // An outstanding fetch and the offset it starts at.
+    pending: Option<(u64, JoinHandle<Result<Vec<u8>>>)>,
+}
+
+impl NfsFile {
+    pub(super) fn new(nfs: Arc<Mutex<Nfs>>, handle: FileHandle, size: u64) -> NfsFile {
+        NfsFile {
+            nfs,
+            handle,
+            size,
+            pos: 0,
+            read_ahead: DEFAULT_READ_AHEAD,
+            buf: Vec::new(),
+            buf_offset: 0,
+            pending: None,
+        }
+    }
+
+    // The size of the file when it was opened.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn set_read_ahead(&mut self, read_ahead: usize) {
+        self.read_ahead = std::cmp::max(read_ahead, 1);
+    }
+
+    fn buf_end(&self) -> u64 {
+        self.buf_offset + self.buf.len() as u64
+    }
+
+    fn fetch(&self, offset: u64) -> JoinHandle<Result<Vec<u8>>> {
+        let nfs = self.nfs.clone();
+        let handle = self.handle;
+        let len = std::cmp::min(self.read_ahead as u64, self.size - offset) as usize;
+
+        // The fetch runs as its own task so it makes progress while the
+        // reader is busy with the data it already has.  It is never aborted
+        // part way through a call; unwanted results are simply dropped.
+        tokio::spawn(async move {
+            let offset = u32::try_from(offset).map_err(|_| Error::OffsetOutOfRange(offset))?;
+            nfs.lock().await.read_range(&handle, offset, len).await
+        })
+    }
+
+    // Starts fetching the data after `buf` if it hasn't been requested yet.
+    fn start_read_ahead(&mut self) {
+        let next = self.buf_end();
+        if self.pending.is_none() && next < self.size {
+            self.pending = Some((next, self.fetch(next)));
+        }
+    }
+}
+
+impl AsyncRead for NfsFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let pos = self.pos;
+            if pos >= self.size || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            if pos >= self.buf_offset && pos < self.buf_end() {
+                let start = (pos - self.buf_offset) as usize;
+                let len = std::cmp::min(buf.remaining(), self.buf.len() - start);
+                buf.put_slice(&self.buf[start..start + len]);
+                self.pos += len as u64;
+                self.start_read_ahead();
+                return Poll::Ready(Ok(()));
+            }
+
+            // Drop a fetch that doesn't cover `pos`, i.e. after a seek.
+            let read_ahead = self.read_ahead as u64;
+            if let Some((offset, _)) = &self.pending {
+                if pos < *offset || pos >= *offset + read_ahead {
+                    self.pending = None;
+                }
+            }
+            if self.pending.is_none() {
+                let fetch = self.fetch(pos);
+                self.pending = Some((pos, fetch));
+            }
+
+            let (offset, fetch) = self.pending.as_mut().unwrap();
+            let offset = *offset;
+            let result = match Pin::new(fetch).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => result,
+            };
+            self.pending = None;
+
+            let data = result
+                .map_err(io::Error::other)?
+                .map_err(io::Error::other)?;
+            if offset + (data.len() as u64) <= pos {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shorter than its attributes",
+                )));
+            }
+            self.buf = data;
+            self.buf_offset = offset;
+        }
+    }
+}
+
+impl AsyncSeek for NfsFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let pos = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.size as i64).checked_add(offset).map(|p| p as u64),
+            SeekFrom::Current(offset) => (self.pos as i64).checked_add(offset).map(|p| p as u64),
+        };
+
+        match pos {
+            Some(pos) if (pos as i64) >= 0 => {
+                self.pos = pos;
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    use super::super::{mock, rpc::RpcConfig};
+    use super::*;
+
+    async fn open_test_file(data: &[u8]) -> NfsFile {
+        let addr = mock::serve(mock::read_handler(data.to_vec())).await;
+        let nfs = Nfs::connect(addr.ip(), addr.port(), &RpcConfig::default())
+            .await
+            .unwrap();
+        NfsFile::new(Arc::new(Mutex::new(nfs)), [0u8; 32], data.len() as u64)
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_to_end() {
+        let data = test_data(200 * 1024 + 17);
+        let mut file = open_test_file(&data).await;
+        file.set_read_ahead(20 * 1024);
+
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+
+        // Reads at the end of the file return no data.
+        assert_eq!(file.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_seek() {
+        let data = test_data(100 * 1024);
+        let mut file = open_test_file(&data).await;
+
+        let mut buf = [0u8; 100];
+        file.seek(SeekFrom::Start(90 * 1024)).await.unwrap();
+        file.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], &data[90 * 1024..90 * 1024 + 100]);
+
+        // Seeking backwards refetches.
+        file.seek(SeekFrom::Current(-50 * 1024)).await.unwrap();
+        file.read_exact(&mut buf).await.unwrap();
+        let pos = 40 * 1024 + 100;
+        assert_eq!(&buf[..], &data[pos..pos + 100]);
+
+        let pos = file.seek(SeekFrom::End(-10)).await.unwrap();
+        assert_eq!(pos, data.len() as u64 - 10);
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).await.unwrap();
+        assert_eq!(&tail[..], &data[data.len() - 10..]);
+
+        assert!(file.seek(SeekFrom::Current(-200 * 1024)).await.is_err());
+    }
+}
@@ -1,41 +1,78 @@
-use anyhow::anyhow;
-use std::{collections::HashMap, net::IpAddr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
 
 mod bind;
+mod error;
+mod file;
+#[cfg(test)]
+mod mock;
 mod mount;
 mod nfs;
+mod probe;
 mod rpc;
+mod server;
 
-pub use anyhow::Result;
-use bind::Bind;
+use bind::{Bind, PMAP_PORT};
+pub use error::{AcceptStatus, Error, NfsStatus, RejectStatus, Result};
+pub use file::{NfsFile, DEFAULT_READ_AHEAD};
 use mount::Mount;
 use nfs::Nfs;
+pub use nfs::{DirEntry, FileType};
+pub use probe::{Ping, Probe, RpcProgram};
+pub use rpc::{Identity, RpcConfig, Transport};
+pub use server::{NfsServer, ServerConfig};
 
 type FileHandle = [u8; 32];
 
 pub struct NfsClient {
     mount: Mount,
-    nfs: Nfs,
+    // Shared with the files opened through this client.
+    nfs: Arc<Mutex<Nfs>>,
     mounts: HashMap<String, FileHandle>,
 }
 
 impl NfsClient {
     pub async fn connect(ip: IpAddr) -> Result<NfsClient> {
-        let mut bind = Bind::connect(ip).await?;
+        Self::connect_with_config(ip, &RpcConfig::default()).await
+    }
 
-        let mount_port = Mount::lookup_port(&mut bind).await?;
-        let mount = Mount::connect(ip, mount_port).await?;
+    pub async fn connect_with_config(ip: IpAddr, config: &RpcConfig) -> Result<NfsClient> {
+        Self::connect_with_portmap(SocketAddr::new(ip, PMAP_PORT), config).await
+    }
 
-        let nfs_port = Nfs::lookup_port(&mut bind).await?;
-        let nfs = Nfs::connect(ip, nfs_port).await?;
+    // Connects through the portmapper at `portmap` rather than the standard
+    // port, i.e. to reach an `NfsServer` that can't bind port 111.
+    pub async fn connect_with_portmap(
+        portmap: SocketAddr,
+        config: &RpcConfig,
+    ) -> Result<NfsClient> {
+        let ip = portmap.ip();
+        let mut bind = Bind::connect(portmap, config).await?;
+
+        let mount = Mount::connect_with_bind(&mut bind, ip, config).await?;
+        let nfs = Nfs::connect_with_bind(&mut bind, ip, config).await?;
 
         Ok(NfsClient {
             mount,
-            nfs,
+            nfs: Arc::new(Mutex::new(nfs)),
             mounts: HashMap::new(),
         })
     }
 
+    // Lists the RPC programs registered with `ip`'s portmapper and pings
+    // each service, to check that a player's NFS server is reachable.
+    pub async fn probe(ip: IpAddr) -> Result<Probe> {
+        Self::probe_with_portmap(SocketAddr::new(ip, PMAP_PORT), &RpcConfig::default()).await
+    }
+
+    pub async fn probe_with_portmap(portmap: SocketAddr, config: &RpcConfig) -> Result<Probe> {
+        probe::probe(portmap, config).await
+    }
+
     pub async fn exports(&mut self) -> Result<Vec<String>> {
         self.mount.exports().await
     }
@@ -53,46 +90,61 @@ impl NfsClient {
             if path.starts_with(export_path) {
                 let fh = self.mount.mount(export_path).await?;
                 self.mounts.insert(export_path.clone(), fh);
-                return Ok((
-                    self.mounts
-                        .get(export_path)
-                        .ok_or(anyhow!("Can't lookup just mounted filesystem"))?
-                        .clone(),
-                    path.strip_prefix(export_path).unwrap().to_string(),
-                ));
+                return Ok((fh, path.strip_prefix(export_path).unwrap().to_string()));
             }
         }
 
-        Err(anyhow!("Can't find export mount for {}", path.clone()))
+        Err(Error::NoExport(path.to_string()))
     }
 
     pub async fn list_files(&mut self, path: &str) -> Result<Vec<String>> {
         let (mount_handle, path) = self.get_mount(path).await?;
-        let dir_handle = self.nfs.lookup(&mount_handle, &path).await?;
-        self.nfs.readdir(&dir_handle).await
+        let mut nfs = self.nfs.lock().await;
+        let dir_handle = nfs.lookup(&mount_handle, &path).await?;
+        nfs.readdir(&dir_handle).await
     }
 
-    pub async fn get_file(&mut self, path: &str) -> Result<Vec<u8>> {
+    // Like `list_files` but with each entry's type, size and modification
+    // time.  "." and ".." are left out.
+    pub async fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
         let (mount_handle, path) = self.get_mount(path).await?;
-        let file_handle = self.nfs.lookup(&mount_handle, &path).await?;
-
-        let attributes = self.nfs.getattr(&file_handle).await?;
-        let file_size = attributes.size as usize;
-        let mut data = vec![0u8; file_size];
-
-        let mut cur_size = 0usize;
-
-        while cur_size < file_size {
-            let read_size = self
-                .nfs
-                .read(&file_handle, cur_size as u32, &mut data[cur_size..])
-                .await?;
+        let mut nfs = self.nfs.lock().await;
+        let dir_handle = nfs.lookup(&mount_handle, &path).await?;
+        nfs.list_dir(&dir_handle).await
+    }
 
-            cur_size += read_size as usize;
+    pub async fn get_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let (mount_handle, path) = self.get_mount(path).await?;
+        let mut nfs = self.nfs.lock().await;
+        let file_handle = nfs.lookup(&mount_handle, &path).await?;
+
+        let attributes = nfs.getattr(&file_handle).await?;
+        let data = nfs
+            .read_range(&file_handle, 0, attributes.size as usize)
+            .await?;
+        if data.len() < attributes.size as usize {
+            return Err(Error::ShortRead {
+                expected: attributes.size as u64,
+                actual: data.len() as u64,
+            });
         }
-
         Ok(data)
     }
+
+    // Opens `path` for streaming.  Unlike `get_file`, only the parts of the
+    // file that are read are fetched.
+    pub async fn open(&mut self, path: &str) -> Result<NfsFile> {
+        let (mount_handle, path) = self.get_mount(path).await?;
+        let mut nfs = self.nfs.lock().await;
+        let file_handle = nfs.lookup(&mount_handle, &path).await?;
+        let attributes = nfs.getattr(&file_handle).await?;
+
+        Ok(NfsFile::new(
+            self.nfs.clone(),
+            file_handle,
+            attributes.size as u64,
+        ))
+    }
 }
 
 #[cfg(test)]
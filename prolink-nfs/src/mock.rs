@@ -0,0 +1,198 @@
+// UDP and TCP RPC servers for tests.
+use std::{io::Cursor, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::Mutex,
+};
+use xdr_codec::Pack;
+
+use super::nfs::xdr as nfs_xdr;
+use super::rpc::xdr;
+
+// What to do with a call.  Replies carry the encoded procedure results.
+// `Raw` replies are sent after the XID in place of an accepted reply header.
+// `Close` drops a TCP connection without answering and is the same as `Drop`
+// over UDP.
+pub(crate) enum Reply {
+    Send(Vec<u8>),
+    Delay(Duration, Vec<u8>),
+    Raw(Vec<u8>),
+    Drop,
+    Close,
+}
+
+// What `respond` makes of a call.
+enum Response {
+    Reply(Duration, Vec<u8>),
+    Drop,
+    Close,
+}
+
+// Answers calls with `handler`, which is given the call header and a cursor
+// positioned at its arguments.  Returns the server's address.
+pub(crate) async fn serve<F>(mut handler: F) -> SocketAddr
+where
+    F: FnMut(&xdr::call_body, &mut Cursor<Vec<u8>>) -> Reply + Send + 'static,
+{
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = socket.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            let (len, src) = match socket.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(_) => return,
+            };
+            let (delay, reply) = match respond(&mut handler, &buf[..len]) {
+                Response::Reply(delay, reply) => (delay, reply),
+                Response::Drop | Response::Close => continue,
+            };
+
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                socket.send_to(&reply, src).await.ok();
+            });
+        }
+    });
+
+    addr
+}
+
+// Like `serve` but over TCP, with calls and replies record marked.  Each
+// reply is sent as two fragments.
+pub(crate) async fn serve_tcp<F>(handler: F) -> SocketAddr
+where
+    F: FnMut(&xdr::call_body, &mut Cursor<Vec<u8>>) -> Reply + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(Mutex::new(handler));
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            let (mut reader, writer) = stream.into_split();
+            let writer = Arc::new(Mutex::new(writer));
+            tokio::spawn(async move {
+                loop {
+                    let mut marker = [0u8; 4];
+                    if reader.read_exact(&mut marker).await.is_err() {
+                        return;
+                    }
+                    let len = (u32::from_be_bytes(marker) & 0x7fff_ffff) as usize;
+                    let mut call = vec![0u8; len];
+                    if reader.read_exact(&mut call).await.is_err() {
+                        return;
+                    }
+                    let (delay, reply) = match respond(&mut *handler.lock().await, &call) {
+                        Response::Reply(delay, reply) => (delay, reply),
+                        Response::Drop => continue,
+                        Response::Close => {
+                            writer.lock().await.shutdown().await.ok();
+                            return;
+                        }
+                    };
+
+                    let writer = writer.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        let (first, last) = reply.split_at(reply.len() / 2);
+                        let mut record = Vec::new();
+                        record.extend_from_slice(&(first.len() as u32).to_be_bytes());
+                        record.extend_from_slice(first);
+                        record.extend_from_slice(&(last.len() as u32 | 0x8000_0000).to_be_bytes());
+                        record.extend_from_slice(last);
+                        writer.lock().await.write_all(&record).await.ok();
+                    });
+                }
+            });
+        }
+    });
+
+    addr
+}
+
+// Runs `handler` on a call, returning the reply and how long to delay it.
+fn respond<F>(handler: &mut F, call: &[u8]) -> Response
+where
+    F: FnMut(&xdr::call_body, &mut Cursor<Vec<u8>>) -> Reply,
+{
+    let mut c = Cursor::new(call.to_vec());
+    let msg: xdr::rpc_msg = xdr_codec::unpack(&mut c).unwrap();
+    let call = match msg.body {
+        xdr::msg_body::CALL(call) => call,
+        _ => return Response::Drop,
+    };
+    match handler(&call, &mut c) {
+        Reply::Send(res) => Response::Reply(Duration::from_secs(0), encode_reply(msg.xid, &res)),
+        Reply::Delay(delay, res) => Response::Reply(delay, encode_reply(msg.xid, &res)),
+        Reply::Raw(body) => {
+            let mut reply = msg.xid.to_be_bytes().to_vec();
+            reply.extend_from_slice(&body);
+            Response::Reply(Duration::from_secs(0), reply)
+        }
+        Reply::Drop => Response::Drop,
+        Reply::Close => Response::Close,
+    }
+}
+
+pub(crate) fn encode_reply(xid: u32, res: &[u8]) -> Vec<u8> {
+    super::rpc::encode_reply(xid, &Ok(res.to_vec()))
+}
+
+pub(crate) fn attributes(size: u32) -> nfs_xdr::FAttr {
+    let time = nfs_xdr::TimeVal {
+        seconds: 0,
+        useconds: 0,
+    };
+    nfs_xdr::FAttr {
+        type_: nfs_xdr::FType::NFREG,
+        mode: 0o100644,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        size,
+        blocksize: 512,
+        rdev: 0,
+        blocks: size.div_ceil(512),
+        fsid: 1,
+        fileid: 1,
+        atime: time.clone(),
+        mtime: time.clone(),
+        ctime: time,
+    }
+}
+
+// Answers NFS `READ`s of `data`.
+pub(crate) fn read_handler(
+    data: Vec<u8>,
+) -> impl FnMut(&xdr::call_body, &mut Cursor<Vec<u8>>) -> Reply + Send + 'static {
+    short_read_handler(data, usize::MAX)
+}
+
+// Like `read_handler` but never returns more than `max_count` bytes, the way
+// some servers answer large `READ`s.
+pub(crate) fn short_read_handler(
+    data: Vec<u8>,
+    max_count: usize,
+) -> impl FnMut(&xdr::call_body, &mut Cursor<Vec<u8>>) -> Reply + Send + 'static {
+    move |call, args| {
+        assert_eq!(call.proc_, 6);
+        let args: nfs_xdr::ReadArgs = xdr_codec::unpack(args).unwrap();
+        let start = std::cmp::min(args.offset as usize, data.len());
+        let count = std::cmp::min(args.count as usize, max_count);
+        let end = std::cmp::min(start + count, data.len());
+
+        let mut res = Cursor::new(Vec::new());
+        nfs_xdr::ReadRes::NFS_OK(nfs_xdr::ReadResBody {
+            attributes: attributes(data.len() as u32),
+            data: nfs_xdr::NFSData(data[start..end].to_vec()),
+        })
+        .pack(&mut res)
+        .unwrap();
+        Reply::Send(res.into_inner())
+    }
+}
@@ -1,12 +1,9 @@
-use anyhow::anyhow;
 use byteorder::{LittleEndian, WriteBytesExt};
-use std::{
-    io::Cursor,
-    net::{IpAddr, SocketAddr},
-};
+use std::{io::Cursor, net::IpAddr};
 
-use super::bind::{self, Bind};
-use super::rpc::{NoneParam, Rpc};
+use super::bind::Bind;
+use super::error::{Error, StatusResult};
+use super::rpc::{NoneParam, Rpc, RpcConfig};
 use super::FileHandle;
 use crate::Result;
 
@@ -41,12 +38,21 @@ pub(super) struct Mount {
 }
 
 impl Mount {
-    pub async fn lookup_port(bind: &mut Bind) -> Result<u16> {
-        bind.lookup(MOUNTPROG, MOUNTVER, bind::Protocol::UDP).await
+    pub async fn connect_with_bind(
+        bind: &mut Bind,
+        ip: IpAddr,
+        config: &RpcConfig,
+    ) -> Result<Mount> {
+        let rpc = bind
+            .connect_program(ip, MOUNTPROG, MOUNTVER, config)
+            .await?;
+        Ok(Mount { rpc })
     }
 
-    pub async fn connect(ip: IpAddr, port: u16) -> Result<Mount> {
-        let rpc = Rpc::connect(SocketAddr::new(ip, port)).await?;
+    // Connects straight to `port`, skipping the portmapper.
+    #[cfg(test)]
+    pub async fn connect(ip: IpAddr, port: u16, config: &RpcConfig) -> Result<Mount> {
+        let rpc = Rpc::connect(std::net::SocketAddr::new(ip, port), config).await?;
         Ok(Mount { rpc })
     }
 
@@ -56,7 +62,7 @@ impl Mount {
         for point in path_vec {
             c.write_u16::<LittleEndian>(point)?;
         }
-        let status: xdr::FHStatus = self
+        let res: StatusResult<xdr::FHandle> = self
             .rpc
             .call(
                 MOUNTPROG,
@@ -66,9 +72,12 @@ impl Mount {
             )
             .await?;
 
-        match status {
-            xdr::FHStatus::Const0(handle) => Ok(handle.0),
-            _ => Err(anyhow!("mount failed for unkown reason")),
+        match res.0 {
+            Ok(handle) => Ok(handle.0),
+            Err(status) => Err(Error::Mount {
+                path: path.to_string(),
+                status,
+            }),
         }
     }
 
@@ -105,22 +114,25 @@ impl Mount {
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
+    use std::net::{Ipv4Addr, SocketAddr};
 
-    use super::super::bind::{Bind, Protocol};
+    use super::super::bind::{Bind, Protocol, PMAP_PORT};
     use super::*;
 
     #[tokio::test]
     async fn test_loopkup() {
         if false {
             let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 35));
-            let mut bind = Bind::connect(ip).await.unwrap();
+            let config = RpcConfig::default();
+            let mut bind = Bind::connect(SocketAddr::new(ip, PMAP_PORT), &config)
+                .await
+                .unwrap();
             let port = bind
                 .lookup(MOUNTPROG, MOUNTVER, Protocol::UDP)
                 .await
                 .unwrap();
             println!("{:?}", port);
-            let mut mount = Mount::connect(ip, port).await.unwrap();
+            let mut mount = Mount::connect(ip, port, &config).await.unwrap();
             mount.exports().await.ok();
             mount.mount("/C/").await.unwrap();
         }
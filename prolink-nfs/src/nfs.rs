@@ -1,12 +1,12 @@
-use anyhow::anyhow;
-use byteorder::{LittleEndian, WriteBytesExt};
 use std::{
-    io::Cursor,
-    net::{IpAddr, SocketAddr},
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use super::bind::{self, Bind};
-use super::rpc::Rpc;
+use super::bind::Bind;
+use super::error::{Error, NfsStatus, StatusResult};
+use super::rpc::{Rpc, RpcConfig};
 use super::FileHandle;
 use crate::Result;
 
@@ -27,9 +27,14 @@ pub use xdr::FAttr as Attributes;
 pub const NFSPROG: u32 = 100003;
 pub const NFSVER: u32 = 2;
 
+// How many bytes of entries to ask for in each `READDIR`.  Replies have to
+// fit in the client's receive buffer along with the RPC header, so this is
+// kept to the largest `READ`.
+const READDIR_COUNT: u32 = xdr::MAXDATA as u32;
+
 #[repr(u32)]
 #[allow(dead_code, non_camel_case_types)]
-enum NfsProc {
+pub(super) enum NfsProc {
     NULL = 0,
     GETATTR = 1,
     SETATTR = 2,
@@ -50,22 +55,92 @@ enum NfsProc {
     STATFS = 17,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl DirEntry {
+    fn new(name: String, attributes: &Attributes) -> DirEntry {
+        let file_type = match attributes.type_ {
+            xdr::FType::NFREG => FileType::Regular,
+            xdr::FType::NFDIR => FileType::Directory,
+            xdr::FType::NFLNK => FileType::Symlink,
+            _ => FileType::Other,
+        };
+        let mtime = &attributes.mtime;
+
+        DirEntry {
+            name,
+            file_type,
+            size: attributes.size as u64,
+            modified: UNIX_EPOCH
+                + Duration::from_secs(mtime.seconds as u64)
+                + Duration::from_micros(mtime.useconds as u64),
+        }
+    }
+}
+
+// The players' NFS server uses UTF-16LE for names.
+pub(super) fn encode_name(name: &str) -> Vec<u8> {
+    name.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+pub(super) fn decode_name(raw: &[u8]) -> Option<String> {
+    let chunks = raw.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let raw: Vec<u16> = chunks.map(|b| u16::from_le_bytes([b[0], b[1]])).collect();
+    Some(String::from_utf16_lossy(&raw))
+}
+
+fn is_stale(e: &Error) -> bool {
+    e.status() == Some(NfsStatus::Stale)
+}
+
 pub(super) struct Nfs {
     rpc: Rpc,
+    // Handles of looked up names, keyed by their directory's handle.
+    handles: HashMap<(FileHandle, String), FileHandle>,
+    // Set once the server is found to only resolve whole paths.
+    whole_path_lookup: bool,
 }
 
 impl Nfs {
-    pub async fn lookup_port(bind: &mut Bind) -> Result<u16> {
-        bind.lookup(NFSPROG, NFSVER, bind::Protocol::UDP).await
+    pub async fn connect_with_bind(bind: &mut Bind, ip: IpAddr, config: &RpcConfig) -> Result<Nfs> {
+        let rpc = bind.connect_program(ip, NFSPROG, NFSVER, config).await?;
+        Ok(Nfs::new(rpc))
     }
 
-    pub async fn connect(ip: IpAddr, port: u16) -> Result<Nfs> {
-        let rpc = Rpc::connect(SocketAddr::new(ip, port)).await?;
-        Ok(Nfs { rpc })
+    // Connects straight to `port`, skipping the portmapper.
+    #[cfg(test)]
+    pub async fn connect(ip: IpAddr, port: u16, config: &RpcConfig) -> Result<Nfs> {
+        let rpc = Rpc::connect(std::net::SocketAddr::new(ip, port), config).await?;
+        Ok(Nfs::new(rpc))
+    }
+
+    fn new(rpc: Rpc) -> Nfs {
+        Nfs {
+            rpc,
+            handles: HashMap::new(),
+            whole_path_lookup: false,
+        }
     }
 
     pub async fn getattr(&mut self, file: &FileHandle) -> Result<Attributes> {
-        let res: xdr::AttrStat = self
+        let res: StatusResult<Attributes> = self
             .rpc
             .call(
                 NFSPROG,
@@ -75,101 +150,547 @@ impl Nfs {
             )
             .await?;
 
+        res.context(|| "NFS error on getattr".to_string())
+    }
+
+    // Looks up `path` relative to `mount`.  Paths are resolved a component
+    // at a time and the handles along the way are cached, so files in the
+    // same directory share lookups.  Some players can only resolve a whole
+    // path in one `LOOKUP`.  Once that works where the component lookup
+    // failed, the client sticks to whole paths.
+    pub async fn lookup(&mut self, mount: &FileHandle, path: &str) -> Result<FileHandle> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        if components.is_empty() {
+            return Ok(*mount);
+        }
+        if self.whole_path_lookup {
+            return self.lookup_name(mount, path).await;
+        }
+
+        let res = match self.lookup_components(mount, &components).await {
+            // A cached handle may have gone stale, i.e. if the media was
+            // reinserted.
+            Err(e) if is_stale(&e) => {
+                self.handles.clear();
+                self.lookup_components(mount, &components).await
+            }
+            res => res,
+        };
+
+        // Only fall back to looking up the whole path if the server refused
+        // a component.  Timeouts and I/O errors say nothing about how it
+        // resolves names.
         match res {
-            xdr::AttrStat::NFS_OK(attributes) => Ok(attributes),
-            _ => Err(anyhow!("NFS error on geterror")),
+            Err(e) if components.len() > 1 && e.status().is_some() => {
+                match self.lookup_name(mount, path).await {
+                    Ok(handle) => {
+                        self.whole_path_lookup = true;
+                        Ok(handle)
+                    }
+                    Err(_) => Err(e),
+                }
+            }
+            res => res,
         }
     }
-    pub async fn lookup(&mut self, mount: &FileHandle, path: &str) -> Result<FileHandle> {
-        let path_vec: Vec<_> = path.encode_utf16().collect();
-        let mut c = Cursor::new(Vec::<u8>::with_capacity(path_vec.len() * 2));
-        for point in path_vec {
-            c.write_u16::<LittleEndian>(point)?;
+
+    async fn lookup_components(
+        &mut self,
+        mount: &FileHandle,
+        components: &[&str],
+    ) -> Result<FileHandle> {
+        let mut handle = *mount;
+        for component in components {
+            let key = (handle, component.to_string());
+            handle = match self.handles.get(&key) {
+                Some(handle) => *handle,
+                None => {
+                    let handle = self.lookup_name(&handle, component).await?;
+                    self.handles.insert(key, handle);
+                    handle
+                }
+            };
         }
+        Ok(handle)
+    }
 
-        let status: xdr::DirOpRes = self
+    async fn lookup_name(&mut self, dir: &FileHandle, name: &str) -> Result<FileHandle> {
+        let res: StatusResult<xdr::DirOpResBody> = self
             .rpc
             .call(
                 NFSPROG,
                 NFSVER,
                 NfsProc::LOOKUP as u32,
                 &xdr::DirOpArgs {
-                    dir: xdr::FHandle(*mount),
-                    name: xdr::Filename(c.into_inner()),
+                    dir: xdr::FHandle(*dir),
+                    name: xdr::Filename(encode_name(name)),
                 },
             )
             .await?;
 
-        match status {
-            xdr::DirOpRes::NFS_OK(body) => Ok(body.file.0),
-            _ => Err(anyhow!("can't look up dir")),
-        }
+        Ok(res.context(|| format!("can't look up {}", name))?.file.0)
     }
 
-    pub async fn read(&mut self, file: &FileHandle, offset: u32, data: &mut [u8]) -> Result<u32> {
-        let count = std::cmp::min(data.len(), xdr::MAXDATA as usize) as u32;
-        let res: xdr::ReadRes = self
-            .rpc
-            .call(
-                NFSPROG,
-                NFSVER,
-                NfsProc::READ as u32,
-                &xdr::ReadArgs {
-                    file: xdr::FHandle(*file),
-                    offset,
-                    count: count,
-                    totalcount: count,
-                },
-            )
-            .await?;
-        match res {
-            xdr::ReadRes::NFS_OK(body) => {
-                let read_size = body.data.0.len();
-                data[..read_size].copy_from_slice(&body.data.0);
-                Ok(read_size as u32)
+    // Reads up to `len` bytes starting at `offset`.  The range is split into
+    // `MAXDATA` sized `READ`s which are pipelined.  The returned data is only
+    // shorter than `len` if the server reports the end of the file.
+    pub async fn read_range(
+        &mut self,
+        file: &FileHandle,
+        offset: u32,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let max_data = xdr::MAXDATA as usize;
+        let mut data = Vec::with_capacity(len);
+
+        // Servers may answer a READ with fewer bytes than asked for before
+        // the end of the file, so whatever is still missing after a short
+        // reply is asked for again.  Only an empty reply means EOF.
+        while data.len() < len {
+            let start = offset as u64 + data.len() as u64;
+            let remaining = len - data.len();
+            let args = (0..remaining)
+                .step_by(max_data)
+                .map(|chunk| {
+                    let chunk_offset = start + chunk as u64;
+                    if chunk_offset > u32::MAX as u64 {
+                        return Err(Error::OffsetOutOfRange(chunk_offset));
+                    }
+                    let count = std::cmp::min(remaining - chunk, max_data) as u32;
+                    Ok(xdr::ReadArgs {
+                        file: xdr::FHandle(*file),
+                        offset: chunk_offset as u32,
+                        count,
+                        totalcount: count,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let results: Vec<StatusResult<xdr::ReadResBody>> = self
+                .rpc
+                .call_pipelined(NFSPROG, NFSVER, NfsProc::READ as u32, &args)
+                .await?;
+
+            let mut eof = false;
+            for (args, res) in args.iter().zip(results) {
+                let mut body = res.context(|| "NFS error on read".to_string())?;
+                body.data.0.truncate(args.count as usize);
+                let received = body.data.0.len();
+                data.extend_from_slice(&body.data.0);
+                if received == 0 {
+                    eof = true;
+                }
+                if received < args.count as usize {
+                    break;
+                }
+            }
+            if eof {
+                break;
             }
-            _ => Err(anyhow!("NFS error on read")),
         }
+
+        Ok(data)
     }
 
-    fn dir_list_to_vec(list: &Option<Box<xdr::Entry>>, v: &mut Vec<String>) {
-        if let Some(ref m) = list {
-            let raw_path_u8 = m.name.0.clone();
-            if raw_path_u8.len() % 2 != 0 {
-                // XXX: log error
-                return;
+    // Returns the names of all entries in `dir`, including "." and "..".
+    // Large directories take several `READDIR`s.
+    pub async fn readdir(&mut self, dir: &FileHandle) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        let mut cookie = xdr::NFSCookie([0u8; xdr::COOKIESIZE as usize]);
+
+        loop {
+            let res: StatusResult<xdr::ReadDirResBody> = self
+                .rpc
+                .call(
+                    NFSPROG,
+                    NFSVER,
+                    NfsProc::READDIR as u32,
+                    &xdr::ReadDirArgs {
+                        dir: xdr::FHandle(*dir),
+                        cookie,
+                        count: READDIR_COUNT,
+                    },
+                )
+                .await?;
+            let body = res.context(|| "can't read directory".to_string())?;
+
+            let mut last_cookie = None;
+            let mut entry = body.entries;
+            while let Some(e) = entry {
+                // XXX: log names that aren't UTF-16
+                if let Some(name) = decode_name(&e.name.0) {
+                    files.push(name);
+                }
+                last_cookie = Some(e.cookie);
+                entry = e.next;
+            }
+
+            // Each page picks up after the last entry of the previous one.
+            match last_cookie {
+                Some(c) if !body.eof => cookie = c,
+                _ => break,
             }
-            let raw_path: Vec<u16> = raw_path_u8
-                .chunks(2)
-                .map(|b| (b[0] as u16) + ((b[1] as u16) << 8))
-                .collect();
-            let path = String::from_utf16_lossy(&raw_path);
-            v.push(path);
-            Self::dir_list_to_vec(&m.next, v);
         }
+
+        Ok(files)
     }
 
-    pub async fn readdir(&mut self, dir: &FileHandle) -> Result<Vec<String>> {
-        let status: xdr::ReadDirRes = self
+    // Lists `dir` with the attributes of each entry.  The attributes come from
+    // pipelined `LOOKUP`s of the names `READDIR` returns.
+    pub async fn list_dir(&mut self, dir: &FileHandle) -> Result<Vec<DirEntry>> {
+        let names: Vec<String> = self
+            .readdir(dir)
+            .await?
+            .into_iter()
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        let args: Vec<xdr::DirOpArgs> = names
+            .iter()
+            .map(|name| xdr::DirOpArgs {
+                dir: xdr::FHandle(*dir),
+                name: xdr::Filename(encode_name(name)),
+            })
+            .collect();
+
+        let results: Vec<StatusResult<xdr::DirOpResBody>> = self
             .rpc
-            .call(
-                NFSPROG,
-                NFSVER,
-                NfsProc::READDIR as u32,
-                &xdr::ReadDirArgs {
-                    dir: xdr::FHandle(*dir),
-                    cookie: xdr::NFSCookie([0u8; xdr::COOKIESIZE as usize]),
-                    count: 16552,
-                },
-            )
+            .call_pipelined(NFSPROG, NFSVER, NfsProc::LOOKUP as u32, &args)
             .await?;
-        match status {
-            xdr::ReadDirRes::NFS_OK(bodies) => {
-                let mut files = Vec::new();
-                Self::dir_list_to_vec(&bodies.entries, &mut files);
-                Ok(files)
+
+        // Entries that were removed since the `READDIR` are skipped.
+        Ok(names
+            .into_iter()
+            .zip(results)
+            .filter_map(|(name, res)| match res.0 {
+                Ok(body) => Some(DirEntry::new(name, &body.attributes)),
+                Err(_) => None,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::Cursor,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        time::Instant,
+    };
+    use xdr_codec::Pack;
+
+    use super::super::mock::{self, Reply};
+    use super::*;
+
+    // Serves a directory of `(name, size)` files, returning `page_size`
+    // entries per `READDIR`.
+    async fn serve_dir(files: Vec<(String, u32)>, page_size: usize) -> Nfs {
+        let addr = mock::serve(move |call, args| {
+            let mut res = Cursor::new(Vec::new());
+            if call.proc_ == NfsProc::READDIR as u32 {
+                let args: xdr::ReadDirArgs = xdr_codec::unpack(args).unwrap();
+                assert!(args.count <= 8192);
+                let start = u32::from_be_bytes(args.cookie.0) as usize;
+                let end = std::cmp::min(start + page_size, files.len());
+                let mut entries = None;
+                for i in (start..end).rev() {
+                    entries = Some(Box::new(xdr::Entry {
+                        fileId: i as u32,
+                        name: xdr::Filename(encode_name(&files[i].0)),
+                        cookie: xdr::NFSCookie((i as u32 + 1).to_be_bytes()),
+                        next: entries,
+                    }));
+                }
+                xdr::ReadDirRes::NFS_OK(xdr::ReadDirResBody {
+                    entries,
+                    eof: end == files.len(),
+                })
+                .pack(&mut res)
+                .unwrap();
+            } else {
+                let args: xdr::DirOpArgs = xdr_codec::unpack(args).unwrap();
+                let name = decode_name(&args.name.0).unwrap();
+                match files.iter().position(|(n, _)| *n == name) {
+                    Some(i) => {
+                        let mut attributes = mock::attributes(files[i].1);
+                        attributes.mtime.seconds = 1_600_000_000 + i as u32;
+                        if name.starts_with("dir") {
+                            attributes.type_ = xdr::FType::NFDIR;
+                        }
+                        xdr::DirOpRes::NFS_OK(xdr::DirOpResBody {
+                            file: xdr::FHandle([i as u8; 32]),
+                            attributes,
+                        })
+                        .pack(&mut res)
+                        .unwrap();
+                    }
+                    None => {
+                        xdr::Stat::NFSERR_NOENT.pack(&mut res).unwrap();
+                    }
+                }
             }
-            _ => Err(anyhow!("read files in directory")),
+            Reply::Send(res.into_inner())
+        })
+        .await;
+
+        Nfs::connect(addr.ip(), addr.port(), &RpcConfig::default())
+            .await
+            .unwrap()
+    }
+
+    // Serves a tree of `paths`.  The handle of a path is its index.  With
+    // `whole_path_only`, only paths relative to the root can be looked up.
+    async fn serve_tree(paths: &[&str], whole_path_only: bool) -> (Nfs, Arc<AtomicU32>) {
+        let paths: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+        let lookups = Arc::new(AtomicU32::new(0));
+        let server_lookups = lookups.clone();
+        let addr = mock::serve(move |_, args| {
+            server_lookups.fetch_add(1, Ordering::SeqCst);
+            let args: xdr::DirOpArgs = xdr_codec::unpack(args).unwrap();
+            let dir = &paths[args.dir.0[0] as usize];
+            let name = decode_name(&args.name.0).unwrap();
+            let path = if dir.is_empty() {
+                name
+            } else if whole_path_only {
+                String::new()
+            } else {
+                format!("{}/{}", dir, name)
+            };
+
+            let mut res = Cursor::new(Vec::new());
+            match paths.iter().position(|p| !p.is_empty() && *p == path) {
+                Some(i) => xdr::DirOpRes::NFS_OK(xdr::DirOpResBody {
+                    file: xdr::FHandle([i as u8; 32]),
+                    attributes: mock::attributes(0),
+                })
+                .pack(&mut res)
+                .unwrap(),
+                None => xdr::Stat::NFSERR_NOENT.pack(&mut res).unwrap(),
+            };
+            Reply::Send(res.into_inner())
+        })
+        .await;
+
+        let nfs = Nfs::connect(addr.ip(), addr.port(), &RpcConfig::default())
+            .await
+            .unwrap();
+        (nfs, lookups)
+    }
+
+    const TREE: &[&str] = &[
+        "",
+        "PIONEER",
+        "PIONEER/rekordbox",
+        "PIONEER/rekordbox/export.pdb",
+        "PIONEER/rekordbox/exportExt.pdb",
+    ];
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let (mut nfs, lookups) = serve_tree(TREE, false).await;
+        let root = [0u8; 32];
+
+        let handle = nfs
+            .lookup(&root, "PIONEER/rekordbox/export.pdb")
+            .await
+            .unwrap();
+        assert_eq!(handle, [3u8; 32]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+        // The directories are cached.
+        let handle = nfs
+            .lookup(&root, "/PIONEER/rekordbox/exportExt.pdb")
+            .await
+            .unwrap();
+        assert_eq!(handle, [4u8; 32]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 4);
+
+        assert_eq!(nfs.lookup(&root, "").await.unwrap(), root);
+
+        let err = nfs.lookup(&root, "PIONEER/missing").await.unwrap_err();
+        assert_eq!(err.status(), Some(NfsStatus::NoEnt));
+        assert!(!nfs.whole_path_lookup);
+    }
+
+    #[tokio::test]
+    async fn test_whole_path_lookup() {
+        let (mut nfs, lookups) = serve_tree(TREE, true).await;
+        let root = [0u8; 32];
+
+        let handle = nfs
+            .lookup(&root, "PIONEER/rekordbox/export.pdb")
+            .await
+            .unwrap();
+        assert_eq!(handle, [3u8; 32]);
+        assert!(nfs.whole_path_lookup);
+
+        // Later lookups go straight to the whole path.
+        let count = lookups.load(Ordering::SeqCst);
+        let handle = nfs
+            .lookup(&root, "PIONEER/rekordbox/exportExt.pdb")
+            .await
+            .unwrap();
+        assert_eq!(handle, [4u8; 32]);
+        assert_eq!(lookups.load(Ordering::SeqCst), count + 1);
+    }
+
+    #[tokio::test]
+    async fn test_whole_path_lookup_after_timeout() {
+        // Lookups of the first component go unanswered but the whole path
+        // can be looked up.
+        let addr = mock::serve(move |_, args| {
+            let args: xdr::DirOpArgs = xdr_codec::unpack(args).unwrap();
+            if decode_name(&args.name.0).unwrap() == "PIONEER" {
+                return Reply::Drop;
+            }
+            let mut res = Cursor::new(Vec::new());
+            xdr::DirOpRes::NFS_OK(xdr::DirOpResBody {
+                file: xdr::FHandle([3u8; 32]),
+                attributes: mock::attributes(0),
+            })
+            .pack(&mut res)
+            .unwrap();
+            Reply::Send(res.into_inner())
+        })
+        .await;
+        let config = RpcConfig {
+            timeout: Duration::from_millis(20),
+            max_timeout: Duration::from_millis(20),
+            retries: 1,
+            ..RpcConfig::default()
+        };
+        let mut nfs = Nfs::connect(addr.ip(), addr.port(), &config).await.unwrap();
+
+        let res = nfs.lookup(&[0u8; 32], "PIONEER/rekordbox/export.pdb").await;
+        assert!(matches!(res, Err(Error::Timeout { .. })));
+        assert!(!nfs.whole_path_lookup);
+    }
+
+    #[tokio::test]
+    async fn test_list_dir() {
+        let mut files: Vec<(String, u32)> = vec![(".".to_string(), 0), ("..".to_string(), 0)];
+        files.push(("dir 🎧".to_string(), 0));
+        for i in 0..20 {
+            files.push((format!("{:04}.DAT", i), i * 100));
+        }
+        let mut nfs = serve_dir(files.clone(), 3).await;
+
+        let names = nfs.readdir(&[0u8; 32]).await.unwrap();
+        let expected: Vec<String> = files.iter().map(|(n, _)| n.clone()).collect();
+        assert_eq!(names, expected);
+
+        let entries = nfs.list_dir(&[0u8; 32]).await.unwrap();
+        assert_eq!(entries.len(), files.len() - 2);
+        assert_eq!(entries[0].name, "dir 🎧");
+        assert_eq!(entries[0].file_type, FileType::Directory);
+        assert_eq!(
+            entries[5],
+            DirEntry {
+                name: "0004.DAT".to_string(),
+                file_type: FileType::Regular,
+                size: 400,
+                modified: UNIX_EPOCH + Duration::from_secs(1_600_000_007),
+            }
+        );
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_read() {
+        // Nothing is answered until `window` different READs have arrived,
+        // which only happens if they're all in flight at once.  The held
+        // back calls are answered when they're retransmitted.
+        let data = test_data(64 * 1024 + 5);
+        let window = 4;
+        let held = Arc::new(Mutex::new(HashSet::new()));
+        let server_held = held.clone();
+        let mut handler = mock::read_handler(data.clone());
+        let addr = mock::serve(move |call, args| {
+            let mut held = server_held.lock().unwrap();
+            if held.len() < window {
+                let read: xdr::ReadArgs = xdr_codec::unpack(&mut args.clone()).unwrap();
+                held.insert(read.offset);
+                if held.len() < window {
+                    return Reply::Drop;
+                }
+            }
+            handler(call, args)
+        })
+        .await;
+        let config = RpcConfig {
+            window,
+            timeout: Duration::from_millis(20),
+            max_timeout: Duration::from_millis(100),
+            ..RpcConfig::default()
+        };
+        let mut nfs = Nfs::connect(addr.ip(), addr.port(), &config).await.unwrap();
+
+        let read = nfs
+            .read_range(&[0u8; 32], 0, data.len() + 100)
+            .await
+            .unwrap();
+        assert_eq!(read, data);
+        assert_eq!(held.lock().unwrap().len(), window);
+    }
+
+    // Reads `data` from a server that takes `latency` to answer each call.
+    async fn timed_read(data: &[u8], latency: Duration, window: usize) -> Duration {
+        let mut handler = mock::read_handler(data.to_vec());
+        let addr = mock::serve(move |call, args| match handler(call, args) {
+            Reply::Send(res) => Reply::Delay(latency, res),
+            reply => reply,
+        })
+        .await;
+        let config = RpcConfig {
+            window,
+            ..RpcConfig::default()
+        };
+        let mut nfs = Nfs::connect(addr.ip(), addr.port(), &config).await.unwrap();
+
+        let start = Instant::now();
+        let read = nfs.read_range(&[0u8; 32], 0, data.len()).await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(read, data);
+        elapsed
+    }
+
+    // Compares one READ at a time with pipelined READs against a server with
+    // 5ms of latency.  Run with `cargo test -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_pipelined_read() {
+        let data = test_data(1024 * 1024);
+        let latency = Duration::from_millis(5);
+        for window in [1, 2, 4, 8] {
+            let elapsed = timed_read(&data, latency, window).await;
+            println!(
+                "window {:2}: {:?}, {:.0} KiB/s",
+                window,
+                elapsed,
+                data.len() as f64 / 1024.0 / elapsed.as_secs_f64()
+            );
         }
     }
+
+    #[tokio::test]
+    async fn test_short_reads() {
+        let data = test_data(20000);
+        let addr = mock::serve(mock::short_read_handler(data.clone(), 3000)).await;
+        let mut nfs = Nfs::connect(addr.ip(), addr.port(), &RpcConfig::default())
+            .await
+            .unwrap();
+
+        let read = nfs.read_range(&[0u8; 32], 0, data.len()).await.unwrap();
+        assert_eq!(read, data);
+        let read = nfs.read_range(&[0u8; 32], 100, 10000).await.unwrap();
+        assert_eq!(read, &data[100..10100]);
+        let read = nfs.read_range(&[0u8; 32], 19000, 10000).await.unwrap();
+        assert_eq!(read, &data[19000..]);
+    }
 }
@@ -0,0 +1,212 @@
+// Health checks for a host's RPC services: what its portmapper has
+// registered and whether each service answers.
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::bind::{self, Bind, Mapping, RPCBPROG};
+use super::mount::MOUNTPROG;
+use super::nfs::NFSPROG;
+use super::rpc::{Rpc, RpcConfig};
+use crate::Result;
+
+// A program registered with a portmapper.  `protocol` is the IP protocol
+// number, 6 for TCP and 17 for UDP.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RpcProgram {
+    pub program: u32,
+    pub version: u32,
+    pub protocol: u32,
+    pub port: u16,
+}
+
+impl RpcProgram {
+    pub fn name(&self) -> Option<&'static str> {
+        match self.program {
+            RPCBPROG => Some("portmapper"),
+            MOUNTPROG => Some("mount"),
+            NFSPROG => Some("nfs"),
+            _ => None,
+        }
+    }
+
+    pub fn protocol_name(&self) -> Option<&'static str> {
+        match self.protocol {
+            p if p == bind::Protocol::TCP as u32 => Some("tcp"),
+            p if p == bind::Protocol::UDP as u32 => Some("udp"),
+            _ => None,
+        }
+    }
+}
+
+impl From<&Mapping> for RpcProgram {
+    fn from(mapping: &Mapping) -> RpcProgram {
+        RpcProgram {
+            program: mapping.prog,
+            version: mapping.vers,
+            protocol: mapping.prot,
+            port: mapping.port as u16,
+        }
+    }
+}
+
+// How long a program took to answer its `NULL` procedure.
+#[derive(Debug)]
+pub struct Ping {
+    pub program: RpcProgram,
+    pub latency: Result<Duration>,
+}
+
+#[derive(Debug)]
+pub struct Probe {
+    pub portmap_latency: Duration,
+    pub programs: Vec<RpcProgram>,
+    // One for each program registered for TCP or UDP.
+    pub pings: Vec<Ping>,
+}
+
+pub(super) async fn probe(portmap: SocketAddr, config: &RpcConfig) -> Result<Probe> {
+    let mut bind = Bind::connect(portmap, config).await?;
+    let start = Instant::now();
+    bind.ping().await?;
+    let portmap_latency = start.elapsed();
+
+    let programs: Vec<RpcProgram> = bind.list().await?.iter().map(RpcProgram::from).collect();
+
+    let mut pings = Vec::new();
+    for program in programs.iter().filter(|p| p.protocol_name().is_some()) {
+        let addr = SocketAddr::new(portmap.ip(), program.port);
+        pings.push(Ping {
+            program: program.clone(),
+            latency: ping(addr, program, config).await,
+        });
+    }
+
+    Ok(Probe {
+        portmap_latency,
+        programs,
+        pings,
+    })
+}
+
+async fn ping(addr: SocketAddr, program: &RpcProgram, config: &RpcConfig) -> Result<Duration> {
+    let start = Instant::now();
+    let mut rpc = if program.protocol == bind::Protocol::TCP as u32 {
+        Rpc::connect_tcp(addr, config).await?
+    } else {
+        Rpc::connect(addr, config).await?
+    };
+    rpc.null(program.program, program.version).await?;
+    Ok(start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        net::{IpAddr, Ipv4Addr},
+    };
+    use tokio::net::UdpSocket;
+    use xdr_codec::Pack;
+
+    use super::super::mock::{self, Reply};
+    use super::super::mount::MOUNTVER;
+    use super::super::nfs::NFSVER;
+    use super::super::{Error, NfsServer, ServerConfig};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_server() {
+        let root = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            portmap_port: 0,
+            ..ServerConfig::default()
+        };
+        let server = NfsServer::bind(IpAddr::V4(Ipv4Addr::LOCALHOST), root.path(), &config)
+            .await
+            .unwrap();
+        let portmap = server.portmap_addr().unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(server.run());
+
+        let probe = probe(portmap, &RpcConfig::default()).await.unwrap();
+        let udp = bind::Protocol::UDP as u32;
+        assert_eq!(
+            probe.programs,
+            vec![
+                RpcProgram {
+                    program: MOUNTPROG,
+                    version: MOUNTVER,
+                    protocol: udp,
+                    port,
+                },
+                RpcProgram {
+                    program: NFSPROG,
+                    version: NFSVER,
+                    protocol: udp,
+                    port,
+                },
+            ]
+        );
+        assert_eq!(probe.programs[1].name(), Some("nfs"));
+        assert_eq!(probe.programs[1].protocol_name(), Some("udp"));
+
+        assert_eq!(probe.pings.len(), 2);
+        for ping in &probe.pings {
+            assert!(ping.latency.is_ok(), "{:?}", ping);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_unreachable_service() {
+        // A socket that never answers stands in for a dead NFS service.
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dead_port = dead.local_addr().unwrap().port() as u32;
+
+        let portmap = mock::serve(move |call, _| {
+            let mut res = Cursor::new(Vec::new());
+            if call.proc_ == bind::RpcbProg::DUMP as u32 {
+                let list: bind::xdr::pmaplist_ptr = Some(Box::new(bind::xdr::pmaplist {
+                    map: Mapping {
+                        prog: NFSPROG,
+                        vers: NFSVER,
+                        prot: bind::Protocol::UDP as u32,
+                        port: dead_port,
+                    },
+                    next: Some(Box::new(bind::xdr::pmaplist {
+                        map: Mapping {
+                            prog: NFSPROG,
+                            vers: NFSVER,
+                            prot: bind::Protocol::TCP as u32,
+                            port: dead_port,
+                        },
+                        next: None,
+                    })),
+                }));
+                list.pack(&mut res).unwrap();
+            }
+            Reply::Send(res.into_inner())
+        })
+        .await;
+
+        let config = RpcConfig {
+            timeout: Duration::from_millis(10),
+            max_timeout: Duration::from_millis(20),
+            retries: 1,
+            window: 1,
+            ..RpcConfig::default()
+        };
+        let probe = probe(portmap, &config).await.unwrap();
+        assert_eq!(probe.programs.len(), 2);
+
+        assert_eq!(probe.pings.len(), 2);
+        assert!(matches!(
+            probe.pings[0].latency,
+            Err(Error::Timeout { prog: NFSPROG, .. })
+        ));
+        // Nothing is listening for TCP.
+        assert!(matches!(probe.pings[1].latency, Err(Error::Io(_))));
+        drop(dead);
+    }
+}
@@ -1,10 +1,19 @@
-use std::{io::Cursor, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    net::SocketAddr,
+    time::Duration,
+};
 
-use anyhow::anyhow;
-use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
-use xdr_codec::Pack;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{timeout, timeout_at, Instant},
+};
+use xdr_codec::{Pack, Unpack};
 
-use crate::Result;
+use super::error::{AcceptStatus, RejectStatus};
+use crate::{Error, Result};
 
 #[allow(
     dead_code,
@@ -23,30 +32,287 @@ impl<Out: xdr_codec::Write> Pack<Out> for NoneParam {
         Ok(0usize)
     }
 }
+impl<In: xdr_codec::Read> Unpack<In> for NoneParam {
+    fn unpack(_: &mut In) -> xdr_codec::Result<(Self, usize)> {
+        Ok((NoneParam {}, 0usize))
+    }
+}
 
 const RPCVERS: u32 = 2;
 
+// Set in a TCP record marker on the last fragment of a record.
+const LAST_FRAGMENT: u32 = 0x8000_0000;
+
+// The largest TCP record accepted, the same as the largest UDP datagram.  A
+// longer record is either a broken server or a stream that's out of sync.
+const MAX_RECORD: usize = 64 * 1024;
+
+// The AUTH_SYS credentials calls are made with.  Servers that check
+// credentials see these as the calling host and user.  The default is the
+// anonymous root identity the client has always sent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Identity {
+    pub stamp: u32,
+    pub machine_name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub gids: Vec<u32>,
+}
+
+impl Default for Identity {
+    fn default() -> Identity {
+        Identity {
+            stamp: 0x957b_8703,
+            machine_name: String::new(),
+            uid: 0,
+            gid: 0,
+            gids: Vec::new(),
+        }
+    }
+}
+
+impl Identity {
+    // Limits from RFC 5531.
+    const MAX_MACHINE_NAME: usize = 255;
+    const MAX_GIDS: usize = 16;
+
+    fn encode(&self) -> Result<xdr::opaque_auth> {
+        if self.machine_name.len() > Self::MAX_MACHINE_NAME {
+            return Err(Error::Encode(format!(
+                "credentials: machine name longer than {} bytes",
+                Self::MAX_MACHINE_NAME
+            )));
+        }
+        if self.gids.len() > Self::MAX_GIDS {
+            return Err(Error::Encode(format!(
+                "credentials: more than {} gids",
+                Self::MAX_GIDS
+            )));
+        }
+
+        let mut c = Cursor::new(Vec::new());
+        xdr::authsys_parms {
+            stamp: self.stamp,
+            machinename: self.machine_name.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            gids: self.gids.clone(),
+        }
+        .pack(&mut c)
+        .map_err(|e| Error::Encode(format!("credentials: {}", e)))?;
+
+        Ok(xdr::opaque_auth {
+            flavor: xdr::auth_flavor::AUTH_SYS,
+            body: c.into_inner(),
+        })
+    }
+}
+
+// Which transport clients use to reach mount and NFS.  `Auto` prefers TCP,
+// falling back to UDP when a program isn't registered for TCP or the
+// connection fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Auto,
+}
+
+// Controls how long calls wait for replies.  A call that hasn't been answered
+// within `timeout` is retransmitted, doubling the wait each time up to
+// `max_timeout`, until it has been retransmitted `retries` times.
+//
+// Over TCP calls aren't retransmitted but are given as long to be answered.
+// If the connection drops it's reopened, up to `retries` times per call, and
+// the unanswered calls are sent again.
+//
+// Calls carry `identity` as their credentials.
+//
+// `window` is how many calls pipelined transfers, like file reads, keep in
+// flight at once.
+#[derive(Clone, Debug)]
+pub struct RpcConfig {
+    pub timeout: Duration,
+    pub max_timeout: Duration,
+    pub retries: u32,
+    pub window: usize,
+    pub transport: Transport,
+    pub identity: Identity,
+}
+
+impl Default for RpcConfig {
+    fn default() -> RpcConfig {
+        RpcConfig {
+            timeout: Duration::from_millis(500),
+            max_timeout: Duration::from_secs(4),
+            retries: 5,
+            window: 4,
+            transport: Transport::Udp,
+            identity: Identity::default(),
+        }
+    }
+}
+
+// A call that hasn't been answered yet.
+struct InFlight {
+    // Index into the payloads of `call_pipelined`.
+    index: usize,
+    request: Vec<u8>,
+    timeout: Duration,
+    deadline: Instant,
+    retries: u32,
+}
+
+enum Socket {
+    Udp(UdpSocket),
+    // Replies are read into `pending` until a whole record has arrived.
+    // `synced` is cleared when a record can't be parsed, after which the
+    // connection has to be reopened.
+    Tcp {
+        stream: TcpStream,
+        pending: Vec<u8>,
+        synced: bool,
+    },
+}
+
 pub(super) struct Rpc {
-    socket: UdpSocket,
+    socket: Socket,
     addr: SocketAddr,
     xid: u32,
+    config: RpcConfig,
+    // `config.identity`, encoded once for every call.
+    cred: xdr::opaque_auth,
 }
 
 impl Rpc {
-    pub async fn connect<A: ToSocketAddrs + std::fmt::Debug>(addr: A) -> Result<Rpc> {
+    pub async fn connect<A: ToSocketAddrs + std::fmt::Debug>(
+        addr: A,
+        config: &RpcConfig,
+    ) -> Result<Rpc> {
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let addr = Self::resolve(addr).await?;
+        Self::new(Socket::Udp(socket), addr, config)
+    }
+
+    pub async fn connect_tcp<A: ToSocketAddrs + std::fmt::Debug>(
+        addr: A,
+        config: &RpcConfig,
+    ) -> Result<Rpc> {
+        let addr = Self::resolve(addr).await?;
+        let socket = Self::open_tcp(addr, config).await?;
+        Self::new(socket, addr, config)
+    }
+
+    async fn open_tcp(addr: SocketAddr, config: &RpcConfig) -> Result<Socket> {
+        let stream = timeout(config.max_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TCP connect timed out"))??;
+        stream.set_nodelay(true)?;
+        Ok(Socket::Tcp {
+            stream,
+            pending: Vec::new(),
+            synced: true,
+        })
+    }
+
+    // Reopens a dropped TCP connection and sends `in_flight` again.  Gives up
+    // with `error` for UDP, errors other than I/O errors, or once
+    // `reconnects` reaches the retry limit.
+    async fn reconnect(
+        &mut self,
+        error: Error,
+        reconnects: &mut u32,
+        in_flight: &mut HashMap<u32, InFlight>,
+    ) -> Result<()> {
+        if !self.is_tcp() || !matches!(error, Error::Io(_)) || *reconnects == self.config.retries {
+            return Err(error);
+        }
+        *reconnects += 1;
+        self.socket = Self::open_tcp(self.addr, &self.config).await?;
+
+        let now = Instant::now();
+        for call in in_flight.values_mut() {
+            call.deadline = now + call.timeout;
+            self.send(&call.request).await?;
+        }
+        Ok(())
+    }
+
+    async fn resolve<A: ToSocketAddrs + std::fmt::Debug>(addr: A) -> Result<SocketAddr> {
         let addrs: Vec<SocketAddr> = lookup_host(&addr).await?.collect();
-        if addrs.len() == 0 {
-            return Err(anyhow!("can't lookup {:?}", addr).into());
+        if addrs.is_empty() {
+            return Err(Error::Address(format!("{:?}", addr)));
         }
+        Ok(addrs[0])
+    }
 
+    fn new(socket: Socket, addr: SocketAddr, config: &RpcConfig) -> Result<Rpc> {
         Ok(Rpc {
             socket,
-            addr: addrs[0],
+            addr,
             xid: 0,
+            config: RpcConfig {
+                window: std::cmp::max(config.window, 1),
+                ..config.clone()
+            },
+            cred: config.identity.encode()?,
         })
     }
 
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.socket, Socket::Tcp { .. })
+    }
+
+    async fn send(&mut self, request: &[u8]) -> Result<()> {
+        match &mut self.socket {
+            Socket::Udp(socket) => {
+                let size = socket.send_to(request, &self.addr).await?;
+                if size != request.len() {
+                    return Err(Error::IncompleteWrite);
+                }
+            }
+            Socket::Tcp { stream, .. } => {
+                // Each call is sent as a single fragment record.
+                let marker = request.len() as u32 | LAST_FRAGMENT;
+                let mut record = Vec::with_capacity(request.len() + 4);
+                record.extend_from_slice(&marker.to_be_bytes());
+                record.extend_from_slice(request);
+                stream.write_all(&record).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Receives the next reply.  This is cancel safe so it can be raced
+    // against the retransmission timer.
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        match &mut self.socket {
+            Socket::Udp(socket) => {
+                let mut buf = vec![0u8; 16 * 1024];
+                let (len, _src) = socket.recv_from(&mut buf).await?;
+                buf.truncate(len);
+                Ok(buf)
+            }
+            Socket::Tcp {
+                stream,
+                pending,
+                synced,
+            } => loop {
+                match take_record(pending) {
+                    Ok(Some(record)) => return Ok(record),
+                    Ok(None) => {}
+                    Err(e) => {
+                        *synced = false;
+                        return Err(e);
+                    }
+                }
+                if stream.read_buf(pending).await? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            },
+        }
+    }
+
     pub async fn call<
         P: xdr_codec::Pack<Cursor<Vec<u8>>>,
         R: xdr_codec::Unpack<Cursor<Vec<u8>>>,
@@ -57,50 +323,154 @@ impl Rpc {
         proc: u32,
         payload: &P,
     ) -> Result<R> {
-        let mut c: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        let _xid = self.encode_rpc(&mut c, prog, vers, proc)?;
-        payload
-            .pack(&mut c)
-            .map_err(|e| anyhow!("error encoding payload: {}", e))?;
+        let mut results = self
+            .call_pipelined(prog, vers, proc, std::slice::from_ref(payload))
+            .await?;
+        Ok(results.remove(0))
+    }
 
-        let buf = c.into_inner();
-        let size = self.socket.send_to(&buf, &self.addr).await?;
-        if size != buf.len() {
-            return Err(anyhow!("Incomplete write").into());
-        }
+    // Calls procedure 0 of `prog`, which every program implements as a
+    // no-op, to check the service is answering.
+    pub async fn null(&mut self, prog: u32, vers: u32) -> Result<()> {
+        let _: NoneParam = self.call(prog, vers, 0, &NoneParam {}).await?;
+        Ok(())
+    }
 
-        let mut buf = [0u8; 16 * 1024];
-        let (len, _src) = self.socket.recv_from(&mut buf).await?;
-        let mut c: Cursor<Vec<u8>> = Cursor::new(Vec::from(&buf[0..len]));
+    // Makes one call per payload, keeping up to `window` of them in flight
+    // at once.  Results are returned in the order of `payloads`.
+    pub async fn call_pipelined<
+        P: xdr_codec::Pack<Cursor<Vec<u8>>>,
+        R: xdr_codec::Unpack<Cursor<Vec<u8>>>,
+    >(
+        &mut self,
+        prog: u32,
+        vers: u32,
+        proc: u32,
+        payloads: &[P],
+    ) -> Result<Vec<R>> {
+        let mut results: Vec<Option<R>> = payloads.iter().map(|_| None).collect();
+        let mut in_flight: HashMap<u32, InFlight> = HashMap::new();
+        let mut next = 0;
+        let mut reconnects = 0;
 
-        let rpc_response: xdr::rpc_msg = xdr_codec::unpack(&mut c)
-            .map_err(|e| anyhow!("error decoding rpc_msg response: {}", e))?;
+        if let Socket::Tcp { synced: false, .. } = self.socket {
+            self.socket = Self::open_tcp(self.addr, &self.config).await?;
+        }
 
-        let reply = match rpc_response.body {
-            xdr::msg_body::REPLY(r) => r,
-            _ => {
-                return Err(anyhow!("expected reply: {:?}", rpc_response).into());
-            }
-        };
+        while next < payloads.len() || !in_flight.is_empty() {
+            while next < payloads.len() && in_flight.len() < self.config.window {
+                let mut c: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+                let xid = self.encode_rpc(&mut c, prog, vers, proc)?;
+                payloads[next]
+                    .pack(&mut c)
+                    .map_err(|e| Error::Encode(format!("payload: {}", e)))?;
 
-        let reply = match reply {
-            xdr::reply_body::MSG_ACCEPTED(r) => r,
-            _ => {
-                return Err(anyhow!("non-accepted reply: {:?}", reply).into());
+                let call = InFlight {
+                    index: next,
+                    request: c.into_inner(),
+                    timeout: self.config.timeout,
+                    deadline: Instant::now() + self.config.timeout,
+                    retries: 0,
+                };
+                let sent = self.send(&call.request).await;
+                in_flight.insert(xid, call);
+                next += 1;
+                if let Err(e) = sent {
+                    self.reconnect(e, &mut reconnects, &mut in_flight).await?;
+                }
             }
-        };
 
-        match reply.data {
-            xdr::reply_data::SUCCESS => (),
-            _ => {
-                return Err(anyhow!("unsucessful call: {:?}", reply).into());
+            let deadline = in_flight.values().map(|c| c.deadline).min().unwrap();
+            match timeout_at(deadline, self.recv()).await {
+                Ok(res) => {
+                    let reply = match res {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            self.reconnect(e, &mut reconnects, &mut in_flight).await?;
+                            continue;
+                        }
+                    };
+                    if reply.len() < 4 {
+                        continue;
+                    }
+                    // Replies to other calls, like late answers to calls that
+                    // were already retransmitted and answered, are discarded.
+                    let xid = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+                    if let Some(call) = in_flight.remove(&xid) {
+                        results[call.index] = Some(Self::decode_reply(reply)?);
+                    }
+                }
+                Err(_) => {
+                    let now = Instant::now();
+                    for call in in_flight.values_mut().filter(|c| c.deadline <= now) {
+                        if call.retries == self.config.retries {
+                            return Err(Error::Timeout {
+                                prog,
+                                proc,
+                                retries: self.config.retries,
+                            });
+                        }
+                        // Retransmissions reuse the XID so a reply to any of
+                        // them will do.  TCP delivers the call so only the
+                        // wait is extended.
+                        call.retries += 1;
+                        call.timeout = std::cmp::min(call.timeout * 2, self.config.max_timeout);
+                        call.deadline = now + call.timeout;
+                        if !self.is_tcp() {
+                            self.send(&call.request).await?;
+                        }
+                    }
+                }
             }
         }
 
-        let ret: R =
-            xdr_codec::unpack(&mut c).map_err(|e| anyhow!("error decoding response: {}", e))?;
+        Ok(results.into_iter().map(|r| r.unwrap()).collect())
+    }
+
+    // Decodes a reply header and the results that follow it.  The header is
+    // decoded field by field, rather than as an `rpc_msg`, so the status of
+    // calls that weren't run is kept.
+    fn decode_reply<R: Unpack<Cursor<Vec<u8>>>>(reply: Vec<u8>) -> Result<R> {
+        let mut c: Cursor<Vec<u8>> = Cursor::new(reply);
+
+        let _xid: u32 = decode(&mut c, "xid")?;
+        let msg_type: u32 = decode(&mut c, "msg_type")?;
+        if msg_type != xdr::msg_type::REPLY as u32 {
+            return Err(Error::Decode(format!(
+                "expected reply, got msg_type {}",
+                msg_type
+            )));
+        }
+
+        let reply_stat: u32 = decode(&mut c, "reply_stat")?;
+        if reply_stat == xdr::reply_stat::MSG_DENIED as u32 {
+            let reject_stat: u32 = decode(&mut c, "reject_stat")?;
+            let status = if reject_stat == xdr::reject_stat::RPC_MISMATCH as u32 {
+                let mismatch: xdr::mismatch = decode(&mut c, "mismatch_info")?;
+                RejectStatus::RpcMismatch {
+                    low: mismatch.low,
+                    high: mismatch.high,
+                }
+            } else {
+                RejectStatus::AuthError(decode(&mut c, "auth_stat")?)
+            };
+            return Err(Error::Rejected(status));
+        }
+
+        let _verf: xdr::opaque_auth = decode(&mut c, "verifier")?;
+        let accept_stat: u32 = decode(&mut c, "accept_stat")?;
+        if accept_stat == xdr::accept_stat::PROG_MISMATCH as u32 {
+            let mismatch: xdr::mismatch = decode(&mut c, "mismatch_info")?;
+            return Err(Error::NotAccepted(AcceptStatus::ProgMismatch {
+                low: mismatch.low,
+                high: mismatch.high,
+            }));
+        }
+        if accept_stat != xdr::accept_stat::SUCCESS as u32 {
+            return Err(Error::NotAccepted(AcceptStatus::from_u32(accept_stat)));
+        }
 
-        Ok(ret)
+        decode(&mut c, "response")
     }
 
     fn encode_rpc(
@@ -118,11 +488,7 @@ impl Rpc {
                 prog,
                 vers,
                 proc_: proc,
-                cred: xdr::opaque_auth {
-                    flavor: xdr::auth_flavor::AUTH_SYS,
-                    //body: Vec::from(&b"\xa5\x9cu\x8d\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"[..]),
-                    body: Vec::from(&b"\x95\x7b\x87\x03\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"[..]),
-                },
+                cred: self.cred.clone(),
                 verf: xdr::opaque_auth {
                     flavor: xdr::auth_flavor::AUTH_NONE,
                     body: Vec::from(&b""[..]),
@@ -131,8 +497,494 @@ impl Rpc {
         };
 
         msg.pack(c)
-            .map_err(|e| anyhow!("error encoding rpc: {}", e))?;
+            .map_err(|e| Error::Encode(format!("rpc_msg: {}", e)))?;
 
         Ok(self.xid)
     }
 }
+
+// Encodes an accepted reply to call `xid`.  `res` is either the encoded
+// results or why the call wasn't run.
+pub(super) fn encode_reply(xid: u32, res: &std::result::Result<Vec<u8>, AcceptStatus>) -> Vec<u8> {
+    let mut c = Cursor::new(Vec::new());
+    let verf = xdr::opaque_auth {
+        flavor: xdr::auth_flavor::AUTH_NONE,
+        body: Vec::new(),
+    };
+    let accept_stat = match res {
+        Ok(_) => xdr::accept_stat::SUCCESS as u32,
+        Err(status) => status.to_u32(),
+    };
+
+    // Packing into a `Vec` can't fail.
+    xid.pack(&mut c).unwrap();
+    (xdr::msg_type::REPLY as u32).pack(&mut c).unwrap();
+    (xdr::reply_stat::MSG_ACCEPTED as u32).pack(&mut c).unwrap();
+    verf.pack(&mut c).unwrap();
+    accept_stat.pack(&mut c).unwrap();
+    if let Err(AcceptStatus::ProgMismatch { low, high }) = *res {
+        low.pack(&mut c).unwrap();
+        high.pack(&mut c).unwrap();
+    }
+
+    let mut reply = c.into_inner();
+    if let Ok(body) = res {
+        reply.extend_from_slice(body);
+    }
+    reply
+}
+
+// Removes a whole record from the front of `pending`, joining its
+// fragments.  Returns `None` if the record hasn't been fully received, and an
+// error if it's longer than `MAX_RECORD`.
+fn take_record(pending: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+    let mut record_len = 0;
+    let mut pos = 0;
+    let mut fragments = Vec::new();
+    loop {
+        let header = match pending.get(pos..pos + 4) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let marker = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let len = (marker & !LAST_FRAGMENT) as usize;
+        record_len += len;
+        if record_len > MAX_RECORD {
+            return Err(Error::Decode(format!(
+                "record longer than {} bytes",
+                MAX_RECORD
+            )));
+        }
+        if pending.len() < pos + 4 + len {
+            return Ok(None);
+        }
+        fragments.push(pos + 4..pos + 4 + len);
+        pos += 4 + len;
+        if marker & LAST_FRAGMENT != 0 {
+            let mut record = Vec::with_capacity(record_len);
+            for fragment in fragments {
+                record.extend_from_slice(&pending[fragment]);
+            }
+            pending.drain(..pos);
+            return Ok(Some(record));
+        }
+    }
+}
+
+fn decode<T: Unpack<Cursor<Vec<u8>>>>(c: &mut Cursor<Vec<u8>>, what: &str) -> Result<T> {
+    xdr_codec::unpack(c).map_err(|e| Error::Decode(format!("{}: {}", what, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+    };
+    use tokio::net::UdpSocket;
+
+    use super::super::mock::{self, Reply};
+    use super::*;
+
+    fn test_config() -> RpcConfig {
+        RpcConfig {
+            timeout: Duration::from_millis(20),
+            max_timeout: Duration::from_millis(80),
+            retries: 4,
+            window: 1,
+            ..RpcConfig::default()
+        }
+    }
+
+    // Answers each call with its `u32` argument.
+    fn echo(args: &mut Cursor<Vec<u8>>) -> Vec<u8> {
+        let value: u32 = xdr_codec::unpack(args).unwrap();
+        let mut res = Cursor::new(Vec::new());
+        value.pack(&mut res).unwrap();
+        res.into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_retransmit() {
+        // Drops every other datagram.
+        let received = Arc::new(AtomicU32::new(0));
+        let server_received = received.clone();
+        let addr = mock::serve(move |_, args| {
+            if server_received.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+                Reply::Drop
+            } else {
+                Reply::Send(echo(args))
+            }
+        })
+        .await;
+
+        let mut rpc = Rpc::connect(addr, &test_config()).await.unwrap();
+        for i in 0..10u32 {
+            let value: u32 = rpc.call(1, 1, 1, &i).await.unwrap();
+            assert_eq!(value, i);
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 20);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined() {
+        // Later calls are answered sooner so replies arrive out of order, and
+        // the first transmission of every third call is lost.
+        let mut seen = HashSet::new();
+        let addr = mock::serve(move |_, args| {
+            let value: u32 = xdr_codec::unpack(&mut args.clone()).unwrap();
+            if value % 3 == 0 && seen.insert(value) {
+                return Reply::Drop;
+            }
+            let delay = Duration::from_millis(10 - value as u64 % 10);
+            Reply::Delay(delay, echo(args))
+        })
+        .await;
+
+        let config = RpcConfig {
+            window: 5,
+            ..test_config()
+        };
+        let mut rpc = Rpc::connect(addr, &config).await.unwrap();
+        let payloads: Vec<u32> = (0..20).collect();
+        let values: Vec<u32> = rpc.call_pipelined(1, 1, 1, &payloads).await.unwrap();
+        assert_eq!(values, payloads);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let received = Arc::new(AtomicU32::new(0));
+        let server_received = received.clone();
+        let addr = mock::serve(move |_, _| {
+            server_received.fetch_add(1, Ordering::SeqCst);
+            Reply::Drop
+        })
+        .await;
+
+        let mut rpc = Rpc::connect(addr, &test_config()).await.unwrap();
+        let res: Result<u32> = rpc.call(1, 1, 1, &1u32).await;
+        assert!(matches!(
+            res,
+            Err(Error::Timeout {
+                prog: 1,
+                proc: 1,
+                retries: 4
+            })
+        ));
+        assert_eq!(received.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_late_reply_is_discarded() {
+        // The first call is answered after it has been retransmitted and
+        // answered, so the late reply arrives while the next call is
+        // waiting.
+        let received = Arc::new(AtomicU32::new(0));
+        let server_received = received.clone();
+        let addr =
+            mock::serve(
+                move |_, args| match server_received.fetch_add(1, Ordering::SeqCst) {
+                    0 => Reply::Delay(Duration::from_millis(60), echo(args)),
+                    1 => Reply::Send(echo(args)),
+                    _ => Reply::Delay(Duration::from_millis(90), echo(args)),
+                },
+            )
+            .await;
+
+        let config = RpcConfig {
+            timeout: Duration::from_millis(30),
+            max_timeout: Duration::from_millis(200),
+            retries: 2,
+            window: 1,
+            ..RpcConfig::default()
+        };
+        let mut rpc = Rpc::connect(addr, &config).await.unwrap();
+        let value: u32 = rpc.call(1, 1, 1, &1u32).await.unwrap();
+        assert_eq!(value, 1);
+        let value: u32 = rpc.call(1, 1, 1, &2u32).await.unwrap();
+        assert_eq!(value, 2);
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_xid_is_discarded() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+            let mut c = Cursor::new(Vec::from(&buf[..len]));
+            let msg: xdr::rpc_msg = xdr_codec::unpack(&mut c).unwrap();
+
+            // A reply to some other call, then the real one.
+            let stale = mock::encode_reply(msg.xid.wrapping_sub(1), &echo(&mut c.clone()));
+            socket
+                .send_to(&stale[..stale.len() - 4], src)
+                .await
+                .unwrap();
+            socket.send_to(&[0xff], src).await.unwrap();
+            let reply = mock::encode_reply(msg.xid, &echo(&mut c));
+            socket.send_to(&reply, src).await.unwrap();
+        });
+
+        let mut rpc = Rpc::connect(addr, &test_config()).await.unwrap();
+        let value: u32 = rpc.call(1, 1, 1, &7u32).await.unwrap();
+        assert_eq!(value, 7);
+    }
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_reply_status() {
+        // Procedures 1-3 are answered with the replies below, the rest with
+        // their arguments.
+        let addr = mock::serve(|call, args| match call.proc_ {
+            // Accepted, AUTH_NONE verifier, PROC_UNAVAIL.
+            1 => Reply::Raw(words(&[1, 0, 0, 0, 3])),
+            // Accepted, AUTH_NONE verifier, PROG_MISMATCH 2-3.
+            2 => Reply::Raw(words(&[1, 0, 0, 0, 2, 2, 3])),
+            // Denied, AUTH_ERROR, AUTH_BADCRED.
+            3 => Reply::Raw(words(&[1, 1, 1, 1])),
+            _ => Reply::Send(echo(args)),
+        })
+        .await;
+
+        let mut rpc = Rpc::connect(addr, &test_config()).await.unwrap();
+        let res: Result<u32> = rpc.call(1, 1, 1, &1u32).await;
+        assert!(matches!(
+            res,
+            Err(Error::NotAccepted(AcceptStatus::ProcUnavail))
+        ));
+        let res: Result<u32> = rpc.call(1, 1, 2, &1u32).await;
+        assert!(matches!(
+            res,
+            Err(Error::NotAccepted(AcceptStatus::ProgMismatch {
+                low: 2,
+                high: 3
+            }))
+        ));
+        let res: Result<u32> = rpc.call(1, 1, 3, &1u32).await;
+        assert!(matches!(
+            res,
+            Err(Error::Rejected(RejectStatus::AuthError(1)))
+        ));
+
+        let value: u32 = rpc.call(1, 1, 4, &9u32).await.unwrap();
+        assert_eq!(value, 9);
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        // Replies arrive out of order and split into fragments.
+        let addr = mock::serve_tcp(|_, args| {
+            let value: u32 = xdr_codec::unpack(&mut args.clone()).unwrap();
+            let delay = Duration::from_millis(10 - value as u64 % 10);
+            Reply::Delay(delay, echo(args))
+        })
+        .await;
+
+        let config = RpcConfig {
+            window: 5,
+            ..test_config()
+        };
+        let mut rpc = Rpc::connect_tcp(addr, &config).await.unwrap();
+        assert!(rpc.is_tcp());
+        let payloads: Vec<u32> = (0..20).collect();
+        let values: Vec<u32> = rpc.call_pipelined(1, 1, 1, &payloads).await.unwrap();
+        assert_eq!(values, payloads);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_timeout_does_not_retransmit() {
+        let received = Arc::new(AtomicU32::new(0));
+        let server_received = received.clone();
+        let addr = mock::serve_tcp(move |_, _| {
+            server_received.fetch_add(1, Ordering::SeqCst);
+            Reply::Drop
+        })
+        .await;
+
+        let mut rpc = Rpc::connect_tcp(addr, &test_config()).await.unwrap();
+        let res: Result<u32> = rpc.call(1, 1, 1, &1u32).await;
+        assert!(matches!(res, Err(Error::Timeout { retries: 4, .. })));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_reconnect() {
+        // The connection is dropped the first time call 3 arrives.  The calls
+        // in flight then are sent again over a new connection.
+        let mut closed = false;
+        let received = Arc::new(AtomicU32::new(0));
+        let server_received = received.clone();
+        let addr = mock::serve_tcp(move |_, args| {
+            server_received.fetch_add(1, Ordering::SeqCst);
+            let value: u32 = xdr_codec::unpack(&mut args.clone()).unwrap();
+            if value == 3 && !closed {
+                closed = true;
+                return Reply::Close;
+            }
+            Reply::Send(echo(args))
+        })
+        .await;
+
+        let config = RpcConfig {
+            window: 5,
+            ..test_config()
+        };
+        let mut rpc = Rpc::connect_tcp(addr, &config).await.unwrap();
+        let payloads: Vec<u32> = (0..20).collect();
+        let values: Vec<u32> = rpc.call_pipelined(1, 1, 1, &payloads).await.unwrap();
+        assert_eq!(values, payloads);
+        assert!(received.load(Ordering::SeqCst) > 20);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_reconnect_limit() {
+        let addr = mock::serve_tcp(|_, _| Reply::Close).await;
+
+        let mut rpc = Rpc::connect_tcp(addr, &test_config()).await.unwrap();
+        let res: Result<u32> = rpc.call(1, 1, 1, &1u32).await;
+        assert!(matches!(res, Err(Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_oversized_record() {
+        // The first connection answers with the marker of a 1MB record.  The
+        // next call is made over a new connection, which answers properly.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut first = true;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut marker = [0u8; 4];
+                stream.read_exact(&mut marker).await.unwrap();
+                let len = (u32::from_be_bytes(marker) & !LAST_FRAGMENT) as usize;
+                let mut call = vec![0u8; len];
+                stream.read_exact(&mut call).await.unwrap();
+
+                let reply = if std::mem::take(&mut first) {
+                    vec![0x80, 0x10, 0, 0]
+                } else {
+                    let xid = u32::from_be_bytes([call[0], call[1], call[2], call[3]]);
+                    let mut args = Cursor::new(call[call.len() - 4..].to_vec());
+                    let reply = mock::encode_reply(xid, &echo(&mut args));
+                    let mut record = (reply.len() as u32 | LAST_FRAGMENT).to_be_bytes().to_vec();
+                    record.extend_from_slice(&reply);
+                    record
+                };
+                stream.write_all(&reply).await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    stream.read_to_end(&mut buf).await.ok();
+                });
+            }
+        });
+
+        let mut rpc = Rpc::connect_tcp(addr, &test_config()).await.unwrap();
+        let res: Result<u32> = rpc.call(1, 1, 1, &1u32).await;
+        assert!(matches!(res, Err(Error::Decode(_))));
+        let value: u32 = rpc.call(1, 1, 1, &2u32).await.unwrap();
+        assert_eq!(value, 2);
+    }
+
+    #[test]
+    fn test_take_record() {
+        let mut pending = vec![0, 0, 0, 2, 1, 2, 0x80, 0, 0, 1, 3, 0x80, 0, 0];
+        assert_eq!(take_record(&mut pending).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(pending, vec![0x80, 0, 0]);
+        assert_eq!(take_record(&mut pending).unwrap(), None);
+
+        pending.extend_from_slice(&[2, 4]);
+        assert_eq!(take_record(&mut pending).unwrap(), None);
+        pending.push(5);
+        assert_eq!(take_record(&mut pending).unwrap(), Some(vec![4, 5]));
+        assert!(pending.is_empty());
+
+        // Oversized records are refused from their markers, before they've
+        // been received.
+        let mut pending = vec![0xff, 0xff, 0xff, 0xff];
+        assert!(matches!(take_record(&mut pending), Err(Error::Decode(_))));
+        let mut pending = vec![0, 0x01, 0, 0];
+        pending.resize(4 + 0x10000, 0);
+        pending.extend_from_slice(&[0x80, 0, 0, 1]);
+        assert!(matches!(take_record(&mut pending), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn test_default_identity() {
+        // The credentials the client sent before they were configurable.
+        let cred = Identity::default().encode().unwrap();
+        assert_eq!(cred.flavor, xdr::auth_flavor::AUTH_SYS);
+        assert_eq!(
+            cred.body,
+            b"\x95\x7b\x87\x03\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identity() {
+        let creds = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let server_creds = creds.clone();
+        let addr = mock::serve(move |call, args| {
+            let parms: xdr::authsys_parms =
+                xdr_codec::unpack(&mut Cursor::new(call.cred.body.clone())).unwrap();
+            server_creds.lock().unwrap().push(parms);
+            Reply::Send(echo(args))
+        })
+        .await;
+
+        let identity = Identity {
+            stamp: 42,
+            machine_name: "booth-laptop".to_string(),
+            uid: 1000,
+            gid: 100,
+            gids: vec![100, 20],
+        };
+        let config = RpcConfig {
+            identity: identity.clone(),
+            ..test_config()
+        };
+        let mut rpc = Rpc::connect(addr, &config).await.unwrap();
+        for i in 0..2u32 {
+            let value: u32 = rpc.call(1, 1, 1, &i).await.unwrap();
+            assert_eq!(value, i);
+        }
+
+        let creds = creds.lock().unwrap();
+        assert_eq!(creds.len(), 2);
+        for parms in creds.iter() {
+            assert_eq!(parms.stamp, identity.stamp);
+            assert_eq!(parms.machinename, identity.machine_name);
+            assert_eq!((parms.uid, parms.gid), (identity.uid, identity.gid));
+            assert_eq!(parms.gids, identity.gids);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identity_limits() {
+        let config = RpcConfig {
+            identity: Identity {
+                gids: (0..17).collect(),
+                ..Identity::default()
+            },
+            ..test_config()
+        };
+        let res = Rpc::connect("127.0.0.1:9", &config).await;
+        assert!(matches!(res, Err(Error::Encode(_))));
+
+        let config = RpcConfig {
+            identity: Identity {
+                machine_name: "x".repeat(256),
+                ..Identity::default()
+            },
+            ..test_config()
+        };
+        let res = Rpc::connect("127.0.0.1:9", &config).await;
+        assert!(matches!(res, Err(Error::Encode(_))));
+    }
+}
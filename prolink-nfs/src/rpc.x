@@ -81,6 +81,17 @@ struct opaque_auth {
     opaque body<400>;
 };
 
+/*
+ * The body of AUTH_SYS credentials, from RFC 5531 Appendix A.
+ */
+struct authsys_parms {
+    unsigned int stamp;
+    string machinename<255>;
+    unsigned int uid;
+    unsigned int gid;
+    unsigned int gids<16>;
+};
+
 struct call_body {
     unsigned int rpcvers;       /* must be equal to two (2) */
     unsigned int prog;
@@ -0,0 +1,647 @@
+// An NFSv2 server that exports a local directory, i.e. one laid out like a
+// rekordbox USB, so players can load tracks from it.  It answers the
+// portmapper, mount and NFS procedures the players use.  The export is
+// read-only.
+use log::warn;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs::Metadata,
+    io::{self, Cursor, SeekFrom},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    net::UdpSocket,
+};
+use xdr_codec::{Pack, Unpack};
+
+use super::bind::{self, RpcbProg, RPCBPROG, RPCBVERS};
+use super::error::{AcceptStatus, NfsStatus, StatusResult};
+use super::mount::{self, MountProc, MOUNTPROG, MOUNTVER};
+use super::nfs::{self, decode_name, encode_name, Attributes, NfsProc, NFSPROG, NFSVER};
+use super::rpc::{self, encode_reply};
+use super::FileHandle;
+use crate::Result;
+
+// Caps `READDIR` replies so they fit in the clients' receive buffers.
+const MAX_READDIR_COUNT: usize = 8192;
+
+// Where the server listens and what it exports.  Players find the mount and
+// NFS services through the portmapper, which is on port 111 for real
+// hardware.  Mount and NFS share a socket on `port`; zero picks any free
+// port.  `export` is the path clients mount, "/C/" for a USB slot.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub export: String,
+    pub portmap_port: u16,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            export: "/C/".to_string(),
+            portmap_port: bind::PMAP_PORT,
+            port: 0,
+        }
+    }
+}
+
+pub struct NfsServer {
+    portmap: UdpSocket,
+    socket: UdpSocket,
+    export: Export,
+}
+
+impl NfsServer {
+    pub async fn bind(ip: IpAddr, root: &Path, config: &ServerConfig) -> Result<NfsServer> {
+        let portmap = UdpSocket::bind(SocketAddr::new(ip, config.portmap_port)).await?;
+        let socket = UdpSocket::bind(SocketAddr::new(ip, config.port)).await?;
+
+        Ok(NfsServer {
+            portmap,
+            socket,
+            export: Export {
+                root: root.to_path_buf(),
+                path: config.export.clone(),
+                handles: Handles::new(),
+            },
+        })
+    }
+
+    pub fn portmap_addr(&self) -> Result<SocketAddr> {
+        Ok(self.portmap.local_addr()?)
+    }
+
+    // The address of the mount and NFS services.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // Answers calls until a socket can't receive.
+    pub async fn run(self) -> Result<()> {
+        let port = self.local_addr()?.port();
+        let mut export = self.export;
+        tokio::try_join!(serve_portmap(self.portmap, port), export.serve(self.socket))?;
+        Ok(())
+    }
+}
+
+type CallResult = std::result::Result<Vec<u8>, AcceptStatus>;
+
+fn decode_call(datagram: &[u8]) -> Option<(u32, rpc::xdr::call_body, Cursor<Vec<u8>>)> {
+    let mut c = Cursor::new(datagram.to_vec());
+    let msg: rpc::xdr::rpc_msg = xdr_codec::unpack(&mut c).ok()?;
+    match msg.body {
+        rpc::xdr::msg_body::CALL(call) => Some((msg.xid, call, c)),
+        _ => None,
+    }
+}
+
+fn decode_args<T: Unpack<Cursor<Vec<u8>>>>(
+    args: &mut Cursor<Vec<u8>>,
+) -> std::result::Result<T, AcceptStatus> {
+    xdr_codec::unpack(args).map_err(|_| AcceptStatus::GarbageArgs)
+}
+
+fn encode<T: Pack<Cursor<Vec<u8>>>>(value: &T) -> Vec<u8> {
+    let mut c = Cursor::new(Vec::new());
+    // Packing into a `Vec` can't fail.
+    value.pack(&mut c).unwrap();
+    c.into_inner()
+}
+
+fn encode_status<T: Pack<Cursor<Vec<u8>>>>(res: std::result::Result<T, NfsStatus>) -> Vec<u8> {
+    encode(&StatusResult(res))
+}
+
+fn io_status(e: io::Error) -> NfsStatus {
+    match e.kind() {
+        io::ErrorKind::NotFound => NfsStatus::NoEnt,
+        io::ErrorKind::PermissionDenied => NfsStatus::Access,
+        _ => NfsStatus::Io,
+    }
+}
+
+async fn serve_portmap(socket: UdpSocket, port: u16) -> Result<()> {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await?;
+        let (xid, call, mut args) = match decode_call(&buf[..len]) {
+            Some(call) => call,
+            None => continue,
+        };
+        let res = portmap_call(port, &call, &mut args);
+        // A reply that can't be sent, i.e. to an unreachable client, is the
+        // client's problem.  Keep serving everyone else.
+        if let Err(e) = socket.send_to(&encode_reply(xid, &res), src).await {
+            warn!("can't send reply to {}: {}", src, e);
+        }
+    }
+}
+
+fn portmap_call(port: u16, call: &rpc::xdr::call_body, args: &mut Cursor<Vec<u8>>) -> CallResult {
+    if call.prog != RPCBPROG {
+        return Err(AcceptStatus::ProgUnavail);
+    }
+    if call.vers != RPCBVERS {
+        return Err(AcceptStatus::ProgMismatch {
+            low: RPCBVERS,
+            high: RPCBVERS,
+        });
+    }
+
+    let mappings = [
+        (MOUNTPROG, MOUNTVER, bind::Protocol::UDP as u32, port as u32),
+        (NFSPROG, NFSVER, bind::Protocol::UDP as u32, port as u32),
+    ];
+    match call.proc_ {
+        p if p == RpcbProg::NULL as u32 => Ok(Vec::new()),
+        p if p == RpcbProg::GETPORT as u32 => {
+            let m: bind::Mapping = decode_args(args)?;
+            let port = mappings
+                .iter()
+                .find(|(prog, vers, prot, _)| (*prog, *vers, *prot) == (m.prog, m.vers, m.prot))
+                .map(|(_, _, _, port)| *port)
+                .unwrap_or(0);
+            Ok(encode(&port))
+        }
+        p if p == RpcbProg::DUMP as u32 => {
+            let mut list: bind::xdr::pmaplist_ptr = None;
+            for (prog, vers, prot, port) in mappings.iter().rev() {
+                list = Some(Box::new(bind::xdr::pmaplist {
+                    map: bind::Mapping {
+                        prog: *prog,
+                        vers: *vers,
+                        prot: *prot,
+                        port: *port,
+                    },
+                    next: list,
+                }));
+            }
+            Ok(encode(&list))
+        }
+        _ => Err(AcceptStatus::ProcUnavail),
+    }
+}
+
+// Maps file handles to paths relative to the export's root.  A handle holds
+// the server's generation and the index of its path, so handles from an
+// earlier run are reported as stale.
+struct Handles {
+    generation: u32,
+    paths: Vec<PathBuf>,
+    ids: HashMap<PathBuf, u32>,
+}
+
+impl Handles {
+    fn new() -> Handles {
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u32)
+            .unwrap_or(0);
+        let mut handles = Handles {
+            generation,
+            paths: Vec::new(),
+            ids: HashMap::new(),
+        };
+        // The root is always the first path.
+        handles.id(Path::new(""));
+        handles
+    }
+
+    fn id(&mut self, path: &Path) -> u32 {
+        if let Some(id) = self.ids.get(path) {
+            return *id;
+        }
+        let id = self.paths.len() as u32;
+        self.paths.push(path.to_path_buf());
+        self.ids.insert(path.to_path_buf(), id);
+        id
+    }
+
+    fn handle(&mut self, path: &Path) -> FileHandle {
+        let mut handle = [0u8; 32];
+        handle[0..4].copy_from_slice(&self.generation.to_be_bytes());
+        handle[4..8].copy_from_slice(&self.id(path).to_be_bytes());
+        handle
+    }
+
+    fn path(&self, handle: &FileHandle) -> std::result::Result<PathBuf, NfsStatus> {
+        let generation = u32::from_be_bytes([handle[0], handle[1], handle[2], handle[3]]);
+        let id = u32::from_be_bytes([handle[4], handle[5], handle[6], handle[7]]);
+        if generation != self.generation {
+            return Err(NfsStatus::Stale);
+        }
+        self.paths.get(id as usize).cloned().ok_or(NfsStatus::Stale)
+    }
+}
+
+struct Export {
+    root: PathBuf,
+    // The path clients mount.
+    path: String,
+    handles: Handles,
+}
+
+impl Export {
+    async fn serve(&mut self, socket: UdpSocket) -> Result<()> {
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            let (len, src) = socket.recv_from(&mut buf).await?;
+            let (xid, call, mut args) = match decode_call(&buf[..len]) {
+                Some(call) => call,
+                None => continue,
+            };
+            let res = self.call(&call, &mut args).await;
+            // A reply that can't be sent, i.e. to an unreachable client, is the
+            // client's problem.  Keep serving everyone else.
+            if let Err(e) = socket.send_to(&encode_reply(xid, &res), src).await {
+                warn!("can't send reply to {}: {}", src, e);
+            }
+        }
+    }
+
+    async fn call(&mut self, call: &rpc::xdr::call_body, args: &mut Cursor<Vec<u8>>) -> CallResult {
+        match (call.prog, call.vers) {
+            (MOUNTPROG, MOUNTVER) => self.mount_call(call.proc_, args),
+            (NFSPROG, NFSVER) => self.nfs_call(call.proc_, args).await,
+            (MOUNTPROG, _) => Err(AcceptStatus::ProgMismatch {
+                low: MOUNTVER,
+                high: MOUNTVER,
+            }),
+            (NFSPROG, _) => Err(AcceptStatus::ProgMismatch {
+                low: NFSVER,
+                high: NFSVER,
+            }),
+            _ => Err(AcceptStatus::ProgUnavail),
+        }
+    }
+
+    fn is_export(&self, path: &str) -> bool {
+        path.trim_end_matches('/') == self.path.trim_end_matches('/')
+    }
+
+    fn mount_call(&mut self, proc: u32, args: &mut Cursor<Vec<u8>>) -> CallResult {
+        match proc {
+            p if p == MountProc::NULL as u32
+                || p == MountProc::UMNT as u32
+                || p == MountProc::UMNTALL as u32 =>
+            {
+                Ok(Vec::new())
+            }
+            p if p == MountProc::MNT as u32 => {
+                let path: mount::xdr::DirPath = decode_args(args)?;
+                let res = match decode_name(&path.0) {
+                    Some(path) if self.is_export(&path) => {
+                        Ok(mount::xdr::FHandle(self.handles.handle(Path::new(""))))
+                    }
+                    _ => Err(NfsStatus::NoEnt),
+                };
+                Ok(encode_status(res))
+            }
+            p if p == MountProc::DUMP as u32 => {
+                Ok(encode(&mount::xdr::MountListRes { next: None }))
+            }
+            p if p == MountProc::EXPORT as u32 => Ok(encode(&mount::xdr::ExportListRes {
+                next: Some(Box::new(mount::xdr::ExportList {
+                    fileSystem: mount::xdr::DirPath(encode_name(&self.path)),
+                    groups: None,
+                    next: None,
+                })),
+            })),
+            _ => Err(AcceptStatus::ProcUnavail),
+        }
+    }
+
+    async fn nfs_call(&mut self, proc: u32, args: &mut Cursor<Vec<u8>>) -> CallResult {
+        let read_only = [
+            NfsProc::SETATTR as u32,
+            NfsProc::WRITE as u32,
+            NfsProc::CREATE as u32,
+            NfsProc::REMOVE as u32,
+            NfsProc::RENAME as u32,
+            NfsProc::LINK as u32,
+            NfsProc::SYMLINK as u32,
+            NfsProc::MKDIR as u32,
+            NfsProc::RMDIR as u32,
+        ];
+        match proc {
+            p if p == NfsProc::NULL as u32 => Ok(Vec::new()),
+            p if p == NfsProc::GETATTR as u32 => {
+                let file: nfs::xdr::FHandle = decode_args(args)?;
+                Ok(encode_status(self.getattr(&file.0).await))
+            }
+            p if p == NfsProc::LOOKUP as u32 => {
+                let args: nfs::xdr::DirOpArgs = decode_args(args)?;
+                Ok(encode_status(self.lookup(&args).await))
+            }
+            p if p == NfsProc::READ as u32 => {
+                let args: nfs::xdr::ReadArgs = decode_args(args)?;
+                Ok(encode_status(self.read(&args).await))
+            }
+            p if p == NfsProc::READDIR as u32 => {
+                let args: nfs::xdr::ReadDirArgs = decode_args(args)?;
+                Ok(encode_status(self.readdir(&args).await))
+            }
+            p if p == NfsProc::STATFS as u32 => Ok(encode_status(Ok(nfs::xdr::StatFSResBody {
+                tsize: nfs::xdr::MAXDATA as u32,
+                bsize: 512,
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+            }))),
+            p if read_only.contains(&p) => Ok(encode(&NfsStatus::RoFs.to_u32())),
+            _ => Err(AcceptStatus::ProcUnavail),
+        }
+    }
+
+    async fn metadata(&self, path: &Path) -> std::result::Result<Metadata, NfsStatus> {
+        tokio::fs::metadata(self.root.join(path))
+            .await
+            .map_err(io_status)
+    }
+
+    fn attributes(&mut self, path: &Path, metadata: &Metadata) -> Attributes {
+        let time = |t: io::Result<SystemTime>| {
+            let since_epoch = t
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            nfs::xdr::TimeVal {
+                seconds: since_epoch.as_secs() as u32,
+                useconds: since_epoch.subsec_micros(),
+            }
+        };
+        let (type_, mode) = if metadata.is_dir() {
+            (nfs::xdr::FType::NFDIR, 0o40755)
+        } else {
+            (nfs::xdr::FType::NFREG, 0o100644)
+        };
+        // NFSv2 sizes are 32 bits.
+        let size = u32::try_from(metadata.len()).unwrap_or(u32::MAX);
+
+        Attributes {
+            type_,
+            mode,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            size,
+            blocksize: 512,
+            rdev: 0,
+            blocks: size.div_ceil(512),
+            fsid: 1,
+            fileid: self.handles.id(path),
+            atime: time(metadata.accessed()),
+            mtime: time(metadata.modified()),
+            ctime: time(metadata.modified()),
+        }
+    }
+
+    async fn getattr(&mut self, file: &FileHandle) -> std::result::Result<Attributes, NfsStatus> {
+        let path = self.handles.path(file)?;
+        let metadata = self.metadata(&path).await?;
+        Ok(self.attributes(&path, &metadata))
+    }
+
+    // Players may look up a whole path in one call, so `name` is resolved a
+    // component at a time.  ".." never leaves the export.
+    async fn lookup(
+        &mut self,
+        args: &nfs::xdr::DirOpArgs,
+    ) -> std::result::Result<nfs::xdr::DirOpResBody, NfsStatus> {
+        let mut path = self.handles.path(&args.dir.0)?;
+        if !self.metadata(&path).await?.is_dir() {
+            return Err(NfsStatus::NotDir);
+        }
+
+        let name = decode_name(&args.name.0).ok_or(NfsStatus::NoEnt)?;
+        for component in name.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    path.pop();
+                }
+                c if c.contains(&['\\', ':'][..]) => return Err(NfsStatus::NoEnt),
+                c => path.push(c),
+            }
+        }
+
+        let metadata = self.metadata(&path).await?;
+        Ok(nfs::xdr::DirOpResBody {
+            file: nfs::xdr::FHandle(self.handles.handle(&path)),
+            attributes: self.attributes(&path, &metadata),
+        })
+    }
+
+    async fn read(
+        &mut self,
+        args: &nfs::xdr::ReadArgs,
+    ) -> std::result::Result<nfs::xdr::ReadResBody, NfsStatus> {
+        let path = self.handles.path(&args.file.0)?;
+        let metadata = self.metadata(&path).await?;
+        if metadata.is_dir() {
+            return Err(NfsStatus::IsDir);
+        }
+
+        let mut file = File::open(self.root.join(&path)).await.map_err(io_status)?;
+        file.seek(SeekFrom::Start(args.offset as u64))
+            .await
+            .map_err(io_status)?;
+        let count = std::cmp::min(args.count, nfs::xdr::MAXDATA as u32);
+        let mut data = Vec::with_capacity(count as usize);
+        file.take(count as u64)
+            .read_to_end(&mut data)
+            .await
+            .map_err(io_status)?;
+
+        Ok(nfs::xdr::ReadResBody {
+            attributes: self.attributes(&path, &metadata),
+            data: nfs::xdr::NFSData(data),
+        })
+    }
+
+    // Entries are sorted by name so cookies, which are the index of the next
+    // entry, stay valid between calls.
+    async fn readdir(
+        &mut self,
+        args: &nfs::xdr::ReadDirArgs,
+    ) -> std::result::Result<nfs::xdr::ReadDirResBody, NfsStatus> {
+        let dir = self.handles.path(&args.dir.0)?;
+        let mut read_dir = tokio::fs::read_dir(self.root.join(&dir))
+            .await
+            .map_err(io_status)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await.map_err(io_status)? {
+            // XXX: log names that aren't unicode
+            if let Some(name) = entry.file_name().to_str() {
+                entries.push(name.to_string());
+            }
+        }
+        entries.sort();
+        let mut names = vec![".".to_string(), "..".to_string()];
+        names.extend(entries);
+
+        // The status, end of list marker and eof flag take 12 bytes, and each
+        // entry 16 plus its padded name.
+        let count = std::cmp::min(args.count as usize, MAX_READDIR_COUNT);
+        let mut size = 12;
+        let start = u32::from_be_bytes(args.cookie.0) as usize;
+        let mut page = Vec::new();
+        for (i, name) in names.iter().enumerate().skip(start) {
+            let encoded = encode_name(name);
+            size += 16 + encoded.len().div_ceil(4) * 4;
+            if size > count {
+                break;
+            }
+            let path = match name.as_str() {
+                "." => dir.clone(),
+                ".." => dir.parent().unwrap_or_else(|| Path::new("")).to_path_buf(),
+                _ => dir.join(name),
+            };
+            page.push((self.handles.id(&path), encoded, i as u32 + 1));
+        }
+
+        let eof = start + page.len() >= names.len();
+        let mut entries = None;
+        for (id, name, cookie) in page.into_iter().rev() {
+            entries = Some(Box::new(nfs::xdr::Entry {
+                fileId: id,
+                name: nfs::xdr::Filename(name),
+                cookie: nfs::xdr::NFSCookie(cookie.to_be_bytes()),
+                next: entries,
+            }));
+        }
+        Ok(nfs::xdr::ReadDirResBody { entries, eof })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::{IpAddr, Ipv4Addr},
+    };
+    use tokio::io::AsyncReadExt;
+
+    use super::super::{FileType, NfsClient, RpcConfig};
+    use super::*;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    // Lays out `root` like a rekordbox USB.
+    fn make_tree(root: &Path) {
+        let rekordbox = root.join("PIONEER/rekordbox");
+        fs::create_dir_all(&rekordbox).unwrap();
+        fs::write(rekordbox.join("export.pdb"), test_data(100 * 1024 + 5)).unwrap();
+        fs::write(rekordbox.join("exportExt.pdb"), test_data(4096)).unwrap();
+
+        let anlz = root.join("PIONEER/USBANLZ/P016/0000875E");
+        fs::create_dir_all(&anlz).unwrap();
+        fs::write(anlz.join("ANLZ0000.DAT"), test_data(3000)).unwrap();
+        fs::write(anlz.join("ANLZ0000.EXT"), test_data(7000)).unwrap();
+
+        let album = root.join("Contents/Björk/Début");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(album.join("Human Behaviour.mp3"), test_data(20000)).unwrap();
+
+        // Enough tracks to take several `READDIR`s.
+        let many = root.join("Contents/Various Artists/Compilation");
+        fs::create_dir_all(&many).unwrap();
+        for i in 0..300 {
+            let name = format!("{:03} A Track With A Fairly Long Name.mp3", i);
+            fs::write(many.join(name), [i as u8]).unwrap();
+        }
+    }
+
+    async fn serve(root: &Path) -> NfsClient {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let config = ServerConfig {
+            portmap_port: 0,
+            ..ServerConfig::default()
+        };
+        let server = NfsServer::bind(ip, root, &config).await.unwrap();
+        let portmap = server.portmap_addr().unwrap();
+        tokio::spawn(server.run());
+
+        NfsClient::connect_with_portmap(portmap, &RpcConfig::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_usb() {
+        let root = tempfile::tempdir().unwrap();
+        make_tree(root.path());
+        let mut client = serve(root.path()).await;
+
+        assert_eq!(client.exports().await.unwrap(), vec!["/C/".to_string()]);
+
+        let pdb = client
+            .get_file("/C/PIONEER/rekordbox/export.pdb")
+            .await
+            .unwrap();
+        assert_eq!(pdb, test_data(100 * 1024 + 5));
+
+        let mut file = client
+            .open("/C/PIONEER/USBANLZ/P016/0000875E/ANLZ0000.EXT")
+            .await
+            .unwrap();
+        let mut anlz = Vec::new();
+        file.read_to_end(&mut anlz).await.unwrap();
+        assert_eq!(anlz, test_data(7000));
+
+        let entries = client.list_dir("/C/Contents/Björk").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "Début");
+        assert_eq!(entries[0].file_type, FileType::Directory);
+
+        let entries = client.list_dir("/C/Contents/Björk/Début").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "Human Behaviour.mp3");
+        assert_eq!(entries[0].file_type, FileType::Regular);
+        assert_eq!(entries[0].size, 20000);
+
+        let entries = client
+            .list_dir("/C/Contents/Various Artists/Compilation")
+            .await
+            .unwrap();
+        let names: Vec<String> = entries.into_iter().map(|e| e.name).collect();
+        let expected: Vec<String> = (0..300)
+            .map(|i| format!("{:03} A Track With A Fairly Long Name.mp3", i))
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    #[tokio::test]
+    async fn test_serve_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("usb");
+        make_tree(&root);
+        fs::write(dir.path().join("outside"), b"secret").unwrap();
+        let mut client = serve(&root).await;
+
+        let err = client
+            .get_file("/C/PIONEER/rekordbox/missing.pdb")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), Some(NfsStatus::NoEnt));
+
+        // Lookups can't escape the export.
+        let err = client.get_file("/C/../outside").await.unwrap_err();
+        assert_eq!(err.status(), Some(NfsStatus::NoEnt));
+
+        let err = client.get_file("/C/PIONEER").await.unwrap_err();
+        assert_eq!(err.status(), Some(NfsStatus::IsDir));
+
+        let err = client.list_files("/B/PIONEER").await.unwrap_err();
+        assert!(matches!(err, crate::Error::NoExport(_)));
+    }
+}
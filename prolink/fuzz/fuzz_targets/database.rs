@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use prolink::database::Database;
+use std::io::Cursor;
+
+// Seeded with src/test-data/export.pdb:
+//   cargo fuzz run database
+fuzz_target!(|data: &[u8]| {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let _ = Database::parse(&mut Cursor::new(data)).await;
+
+        let mut db = Database::default();
+        let _ = db.parse_ext(&mut Cursor::new(data)).await;
+    });
+});
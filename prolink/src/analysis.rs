@@ -1,4 +1,4 @@
-use std::{convert::TryInto, io::SeekFrom, num::Wrapping};
+use std::{io::SeekFrom, num::Wrapping};
 
 use anyhow::anyhow;
 use num_derive::FromPrimitive;
@@ -7,7 +7,7 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 
 use crate::Result;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PhraseType {
     Intro,
     Up,
@@ -18,20 +18,20 @@ pub enum PhraseType {
     Bridge,
 }
 
-#[derive(Debug, FromPrimitive)]
+#[derive(Clone, Debug, FromPrimitive, PartialEq)]
 pub enum Mood {
     High = 1,
     Mid = 2,
     Low = 3,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct PhraseId {
     pub ty: PhraseType,
     pub index: u8,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Phrase {
     pub index: u16,
     pub beats: Vec<u16>,
@@ -39,45 +39,268 @@ pub struct Phrase {
     pub fill_beats: u16,
 }
 
-#[derive(Debug)]
+// Lighting style bank chosen for the track in rekordbox.  Newer releases add
+// banks, which are kept as `Unknown`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bank {
+    Default,
+    Cool,
+    Natural,
+    Hot,
+    Subtle,
+    Warm,
+    Vivid,
+    Club1,
+    Club2,
+    Unknown(u8),
+}
+
+impl From<u8> for Bank {
+    fn from(raw: u8) -> Bank {
+        match raw {
+            0 => Bank::Default,
+            1 => Bank::Cool,
+            2 => Bank::Natural,
+            3 => Bank::Hot,
+            4 => Bank::Subtle,
+            5 => Bank::Warm,
+            6 => Bank::Vivid,
+            7 => Bank::Club1,
+            8 => Bank::Club2,
+            _ => Bank::Unknown(raw),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct SongStructure {
     pub mood: Mood,
     pub end_beat: u16,
-    pub bank: u8,
+    pub bank: Bank,
     pub phrases: Vec<Phrase>,
 }
 
-#[derive(Debug)]
+impl SongStructure {
+    // Returns the phrase containing `beat` along with the number of beats left
+    // before the next phrase (or the end of the last phrase).
+    pub fn phrase_at(&self, beat: u32) -> Option<(&Phrase, u16)> {
+        let i = self
+            .phrases
+            .iter()
+            .rposition(|p| p.beats[0] as u32 <= beat)?;
+        let end = match self.phrases.get(i + 1) {
+            Some(next) => next.beats[0],
+            None => self.end_beat,
+        } as u32;
+
+        if beat >= end {
+            return None;
+        }
+
+        Some((&self.phrases[i], (end - beat) as u16))
+    }
+}
+
+// Column of a monochrome waveform (`PWAV`, `PWV2` and `PWV3`).  Height is
+// 0-31 (0-15 for the tiny preview) and whiteness is 0-7.  The tiny preview
+// has no whiteness so it is always 0 there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MonochromeColumn {
+    pub height: u8,
+    pub whiteness: u8,
+}
+
+// Column of the colour preview (`PWV4`).  The first three bytes are not well
+// understood.  The players draw the red, green and blue bytes as stacked
+// heights with blue being the front-most layer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorPreviewColumn {
+    pub unknown: [u8; 3],
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+// Column of the colour detail waveform (`PWV5`).  Colour channels are 0-7 and
+// height is 0-31.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorDetailColumn {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub height: u8,
+}
+
+// Column of the CDJ-3000 3-band waveforms (`PWV6` and `PWV7`).  Each band is
+// its own height.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThreeBandColumn {
+    pub mid: u8,
+    pub high: u8,
+    pub low: u8,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Waveforms {
+    pub preview: Option<Vec<MonochromeColumn>>,
+    pub tiny_preview: Option<Vec<MonochromeColumn>>,
+    pub detail: Option<Vec<MonochromeColumn>>,
+    pub color_preview: Option<Vec<ColorPreviewColumn>>,
+    pub color_detail: Option<Vec<ColorDetailColumn>>,
+    pub three_band_preview: Option<Vec<ThreeBandColumn>>,
+    pub three_band_detail: Option<Vec<ThreeBandColumn>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridBeat {
+    // 1-4 position of the beat in its bar.
+    pub beat_number: u16,
+    // BPM * 100.
+    pub tempo: u16,
+    // Milliseconds from the start of the track.
+    pub time: u32,
+}
+
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
+pub enum CueType {
+    Point = 1,
+    Loop = 2,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cue {
+    // 0 for memory cues, 1 for hot cue A, 2 for B, etc.
+    pub hot_cue: u32,
+    pub ty: CueType,
+    pub time: u32,
+    pub loop_time: Option<u32>,
+    // Comment and color are only present in extended (`PCO2`) cues.
+    pub comment: String,
+    pub color: Option<[u8; 3]>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Analysis {
     pub structure: Option<SongStructure>,
+    pub waveforms: Waveforms,
+    pub beat_grid: Option<Vec<GridBeat>>,
+    pub cues: Vec<Cue>,
+    pub extended_cues: Vec<Cue>,
 }
 
 impl Analysis {
-    #[allow(dead_code)]
     pub fn new() -> Analysis {
-        Analysis { structure: None }
+        Analysis::default()
+    }
+
+    // Prefer the extended cue list from the `.EXT` file when it has been
+    // parsed since it carries comments and colors.
+    pub fn cue_list(&self) -> &[Cue] {
+        if self.extended_cues.is_empty() {
+            &self.cues
+        } else {
+            &self.extended_cues
+        }
+    }
+
+    // Fills in whatever this analysis is missing from `other`, usually parsed
+    // from another of the track's analysis files.  Sections both carry, like
+    // the `PCOB` cue lists in `.DAT` and `.EXT`, are kept from this one.
+    pub fn merge(&mut self, other: Analysis) {
+        let waveforms = &mut self.waveforms;
+        waveforms.preview = waveforms.preview.take().or(other.waveforms.preview);
+        waveforms.tiny_preview = waveforms
+            .tiny_preview
+            .take()
+            .or(other.waveforms.tiny_preview);
+        waveforms.detail = waveforms.detail.take().or(other.waveforms.detail);
+        waveforms.color_preview = waveforms
+            .color_preview
+            .take()
+            .or(other.waveforms.color_preview);
+        waveforms.color_detail = waveforms
+            .color_detail
+            .take()
+            .or(other.waveforms.color_detail);
+        waveforms.three_band_preview = waveforms
+            .three_band_preview
+            .take()
+            .or(other.waveforms.three_band_preview);
+        waveforms.three_band_detail = waveforms
+            .three_band_detail
+            .take()
+            .or(other.waveforms.three_band_detail);
+
+        self.structure = self.structure.take().or(other.structure);
+        self.beat_grid = self.beat_grid.take().or(other.beat_grid);
+        if self.cues.is_empty() {
+            self.cues = other.cues;
+        }
+        if self.extended_cues.is_empty() {
+            self.extended_cues = other.extended_cues;
+        }
     }
 
-    #[allow(dead_code)]
     pub async fn parse<R: AsyncRead + AsyncSeek + Unpin>(&mut self, r: &mut R) -> Result<()> {
+        let actual_len = r.seek(SeekFrom::End(0)).await?;
         r.seek(SeekFrom::Start(0)).await?;
         let _four_cc = r.read_u32().await?;
         let header_len = r.read_u32().await? as u64;
         let file_len = r.read_u32().await? as u64;
+        if file_len > actual_len {
+            return Err(anyhow!(
+                "file length 0x{:x} past end of file (0x{:x})",
+                file_len,
+                actual_len
+            )
+            .into());
+        }
 
+        // Section headers come from whatever player served the file, so
+        // check them before allocating or looping on them.
         let mut section_offset = header_len;
         while section_offset < file_len {
             r.seek(SeekFrom::Start(section_offset)).await?;
             let section_four_cc = r.read_u32().await?;
             r.seek(SeekFrom::Current(4)).await?;
             let section_len = r.read_u32().await? as u64;
+            if section_len < 12 {
+                return Err(anyhow!(
+                    "section at 0x{:x} too short (0x{:x})",
+                    section_offset,
+                    section_len
+                )
+                .into());
+            }
+            if section_offset + section_len > file_len {
+                return Err(anyhow!(
+                    "section 0x{:x}+0x{:x} past end of file (0x{:x})",
+                    section_offset,
+                    section_len,
+                    file_len
+                )
+                .into());
+            }
 
             let mut data = vec![0; section_len as usize];
             r.seek(SeekFrom::Start(section_offset)).await?;
             r.read_exact(&mut data).await?;
 
-            if section_four_cc == u32::from_be_bytes(*b"PSSI") {
-                self.parse_song_structure(&data)?;
+            match &section_four_cc.to_be_bytes() {
+                b"PSSI" => self.parse_song_structure(&data)?,
+                b"PQTZ" => self.beat_grid = Some(Self::parse_beat_grid(&data)?),
+                b"PCOB" => self.cues.extend(Self::parse_cue_list(&data)?),
+                b"PCO2" => self
+                    .extended_cues
+                    .extend(Self::parse_extended_cue_list(&data)?),
+                b"PWAV" => self.waveforms.preview = Some(Self::parse_monochrome_preview(&data)?),
+                b"PWV2" => self.waveforms.tiny_preview = Some(Self::parse_tiny_preview(&data)?),
+                b"PWV3" => self.waveforms.detail = Some(Self::parse_monochrome_detail(&data)?),
+                b"PWV4" => self.waveforms.color_preview = Some(Self::parse_color_preview(&data)?),
+                b"PWV5" => self.waveforms.color_detail = Some(Self::parse_color_detail(&data)?),
+                b"PWV6" => self.waveforms.three_band_preview = Some(Self::parse_three_band(&data)?),
+                b"PWV7" => self.waveforms.three_band_detail = Some(Self::parse_three_band(&data)?),
+                _ => (),
             }
 
             section_offset += section_len;
@@ -85,43 +308,40 @@ impl Analysis {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn parse_song_structure(&mut self, data: &[u8]) -> Result<()> {
-        let mask = [
-            0xCBu8, 0xE1, 0xEE, 0xFA, 0xE5, 0xEE, 0xAD, 0xEE, 0xE9, 0xD2, 0xE9, 0xEB, 0xE1, 0xE9,
-            0xF3, 0xE8, 0xE9, 0xF4, 0xE1,
-        ];
+        let header_len = be_u32(data, 0x4)? as usize;
         let entry_size = be_u32(data, 0xc)? as usize;
         let num_entries = be_u16(data, 0x10)? as usize;
-        let decoded_data: Vec<_> = data
-            .iter()
-            .enumerate()
-            .map(|(i, &x)| {
-                let mask_index = i + mask.len() - 0x12;
-                let mask_byte =
-                    Wrapping(mask[mask_index % mask.len()]) + Wrapping(num_entries as u8);
-                x ^ mask_byte.0
-            })
-            .collect();
+
+        // Some exports XOR mask everything after the entry count.  Real moods
+        // are small so a large raw mood means the data is masked.
+        let decoded_data = if be_u16(data, 0x12)? > 20 {
+            Self::mask_song_structure(data, num_entries)
+        } else {
+            data.to_vec()
+        };
 
         let raw_mood = be_u16(&decoded_data, 0x12)?;
         let mood: Mood =
             FromPrimitive::from_u16(raw_mood).ok_or(anyhow!("unsupported mood {}", raw_mood))?;
         let end_beat = be_u16(&decoded_data, 0x1a)?;
-        let bank = decoded_data[0x1a];
+        let bank = Bank::from(section_slice(&decoded_data, 0x1e, 1)?[0]);
 
         let mut phrases = Vec::new();
 
         for i in 0..num_entries {
-            let entry_data = &decoded_data[0x20 + i * entry_size..];
+            let entry_data = section_slice(&decoded_data, header_len + i * entry_size, entry_size)?;
+            if entry_data.len() < 0x18 {
+                return Err(anyhow!("song structure entry too short ({})", entry_size).into());
+            }
             let index = be_u16(entry_data, 0x0)?;
             let mut beats = vec![be_u16(entry_data, 0x2)?];
             let kind = be_u16(entry_data, 0x4)?;
             let k1 = entry_data[0x7];
             let k2 = entry_data[0x9];
-            let flags = entry_data[0x9];
-            beats.push(be_u16(entry_data, 0xc)?);
+            let flags = entry_data[0xb];
             if flags == 0x01 {
+                beats.push(be_u16(entry_data, 0xc)?);
                 beats.push(be_u16(entry_data, 0xe)?);
                 beats.push(be_u16(entry_data, 0x10)?);
             }
@@ -152,6 +372,223 @@ impl Analysis {
         Ok(())
     }
 
+    // The mask is keyed on the entry count and starts at the mood field.
+    // Applying it twice gets back the original data.
+    fn mask_song_structure(data: &[u8], num_entries: usize) -> Vec<u8> {
+        let mask = [
+            0xCBu8, 0xE1, 0xEE, 0xFA, 0xE5, 0xEE, 0xAD, 0xEE, 0xE9, 0xD2, 0xE9, 0xEB, 0xE1, 0xE9,
+            0xF3, 0xE8, 0xE9, 0xF4, 0xE1,
+        ];
+        data.iter()
+            .enumerate()
+            .map(|(i, &x)| {
+                if i < 0x12 {
+                    return x;
+                }
+                let mask_byte =
+                    Wrapping(mask[(i - 0x12) % mask.len()]) + Wrapping(num_entries as u8);
+                x ^ mask_byte.0
+            })
+            .collect()
+    }
+
+    pub fn parse_beat_grid(data: &[u8]) -> Result<Vec<GridBeat>> {
+        let header_len = be_u32(data, 0x4)? as usize;
+        let num_beats = be_u32(data, 0x14)? as usize;
+        Ok(section_slice(data, header_len, num_beats * 8)?
+            .chunks(8)
+            .map(|e| GridBeat {
+                beat_number: u16::from_be_bytes([e[0], e[1]]),
+                tempo: u16::from_be_bytes([e[2], e[3]]),
+                time: u32::from_be_bytes([e[4], e[5], e[6], e[7]]),
+            })
+            .collect())
+    }
+
+    // Both cue list formats are a header followed by a list of variable length
+    // entries that each start with their own four cc and lengths.
+    fn cue_entries<'a>(
+        data: &'a [u8],
+        num_entries: usize,
+        entry_four_cc: &[u8; 4],
+    ) -> Result<Vec<&'a [u8]>> {
+        let mut offset = be_u32(data, 0x4)? as usize;
+        let mut entries = Vec::new();
+        for _ in 0..num_entries {
+            let four_cc = section_slice(data, offset, 4)?;
+            if four_cc != entry_four_cc {
+                return Err(anyhow!("unexpected cue entry {:x?}", four_cc).into());
+            }
+            let entry_len = be_u32(data, offset + 0x8)? as usize;
+            entries.push(section_slice(data, offset, entry_len)?);
+            offset += entry_len;
+        }
+        Ok(entries)
+    }
+
+    fn cue_type(raw_type: u8) -> Result<CueType> {
+        FromPrimitive::from_u8(raw_type)
+            .ok_or_else(|| anyhow!("unknown cue type {}", raw_type).into())
+    }
+
+    fn loop_time(raw_loop_time: u32) -> Option<u32> {
+        if raw_loop_time == 0xffffffff {
+            None
+        } else {
+            Some(raw_loop_time)
+        }
+    }
+
+    pub fn parse_cue_list(data: &[u8]) -> Result<Vec<Cue>> {
+        let num_entries = be_u16(data, 0x12)? as usize;
+        Self::cue_entries(data, num_entries, b"PCPT")?
+            .iter()
+            .map(|e| {
+                Ok(Cue {
+                    hot_cue: be_u32(e, 0xc)?,
+                    ty: Self::cue_type(section_slice(e, 0x1c, 1)?[0])?,
+                    time: be_u32(e, 0x20)?,
+                    loop_time: Self::loop_time(be_u32(e, 0x24)?),
+                    comment: String::new(),
+                    color: None,
+                })
+            })
+            .collect()
+    }
+
+    pub fn parse_extended_cue_list(data: &[u8]) -> Result<Vec<Cue>> {
+        let num_entries = be_u16(data, 0x10)? as usize;
+        Self::cue_entries(data, num_entries, b"PCP2")?
+            .iter()
+            .map(|e| {
+                // Older exports end the entry before the comment.
+                let comment_len = if e.len() > 0x2c {
+                    be_u32(e, 0x28)? as usize
+                } else {
+                    0
+                };
+                if comment_len % 2 != 0 {
+                    return Err(anyhow!("odd cue comment length {}", comment_len).into());
+                }
+                let comment: Vec<u16> = section_slice(e, 0x2c, comment_len)?
+                    .chunks_exact(2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    .take_while(|&c| c != 0)
+                    .collect();
+                let color = match e.get((0x2c + comment_len)..(0x30 + comment_len)) {
+                    Some(&[code, r, g, b]) if code != 0 => Some([r, g, b]),
+                    _ => None,
+                };
+
+                Ok(Cue {
+                    hot_cue: be_u32(e, 0xc)?,
+                    ty: Self::cue_type(section_slice(e, 0x10, 1)?[0])?,
+                    time: be_u32(e, 0x14)?,
+                    loop_time: Self::loop_time(be_u32(e, 0x18)?),
+                    comment: String::from_utf16_lossy(&comment),
+                    color,
+                })
+            })
+            .collect()
+    }
+
+    // `PWAV` and `PWV2` sections have a data length at 0xc followed by one byte
+    // per column.
+    fn preview_data(data: &[u8]) -> Result<&[u8]> {
+        let header_len = be_u32(data, 0x4)? as usize;
+        let data_len = be_u32(data, 0xc)? as usize;
+        section_slice(data, header_len, data_len)
+    }
+
+    // The other waveform sections have an entry size at 0xc and an entry count
+    // at 0x10.
+    fn entry_data(data: &[u8], expected_entry_size: usize) -> Result<&[u8]> {
+        let header_len = be_u32(data, 0x4)? as usize;
+        let entry_size = be_u32(data, 0xc)? as usize;
+        let num_entries = be_u32(data, 0x10)? as usize;
+        if entry_size != expected_entry_size {
+            return Err(anyhow!(
+                "unexpected waveform entry size {} (expected {})",
+                entry_size,
+                expected_entry_size
+            )
+            .into());
+        }
+        let len = entry_size
+            .checked_mul(num_entries)
+            .ok_or_else(|| anyhow!("too many waveform entries ({})", num_entries))?;
+        section_slice(data, header_len, len)
+    }
+
+    fn monochrome_column(b: u8) -> MonochromeColumn {
+        MonochromeColumn {
+            height: b & 0x1f,
+            whiteness: b >> 5,
+        }
+    }
+
+    pub fn parse_monochrome_preview(data: &[u8]) -> Result<Vec<MonochromeColumn>> {
+        Ok(Self::preview_data(data)?
+            .iter()
+            .map(|&b| Self::monochrome_column(b))
+            .collect())
+    }
+
+    pub fn parse_tiny_preview(data: &[u8]) -> Result<Vec<MonochromeColumn>> {
+        Ok(Self::preview_data(data)?
+            .iter()
+            .map(|&b| MonochromeColumn {
+                height: b & 0x0f,
+                whiteness: 0,
+            })
+            .collect())
+    }
+
+    pub fn parse_monochrome_detail(data: &[u8]) -> Result<Vec<MonochromeColumn>> {
+        Ok(Self::entry_data(data, 1)?
+            .iter()
+            .map(|&b| Self::monochrome_column(b))
+            .collect())
+    }
+
+    pub fn parse_color_preview(data: &[u8]) -> Result<Vec<ColorPreviewColumn>> {
+        Ok(Self::entry_data(data, 6)?
+            .chunks(6)
+            .map(|e| ColorPreviewColumn {
+                unknown: [e[0], e[1], e[2]],
+                red: e[3],
+                green: e[4],
+                blue: e[5],
+            })
+            .collect())
+    }
+
+    pub fn parse_color_detail(data: &[u8]) -> Result<Vec<ColorDetailColumn>> {
+        Ok(Self::entry_data(data, 2)?
+            .chunks(2)
+            .map(|e| {
+                let v = u16::from_be_bytes([e[0], e[1]]);
+                ColorDetailColumn {
+                    red: (v >> 13) as u8 & 0x7,
+                    green: (v >> 10) as u8 & 0x7,
+                    blue: (v >> 7) as u8 & 0x7,
+                    height: (v >> 2) as u8 & 0x1f,
+                }
+            })
+            .collect())
+    }
+
+    pub fn parse_three_band(data: &[u8]) -> Result<Vec<ThreeBandColumn>> {
+        Ok(Self::entry_data(data, 3)?
+            .chunks(3)
+            .map(|e| ThreeBandColumn {
+                mid: e[0],
+                high: e[1],
+                low: e[2],
+            })
+            .collect())
+    }
+
     fn get_phrase_id(mood: &Mood, kind: u16, k1: u8, k2: u8, k3: u8) -> Result<PhraseId> {
         match mood {
             Mood::High => Self::get_high_phrase_id(kind, k1, k2, k3),
@@ -271,20 +708,27 @@ impl Analysis {
     }
 }
 
+fn section_slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    let end = offset.checked_add(len);
+    end.and_then(|end| data.get(offset..end)).ok_or_else(|| {
+        anyhow!(
+            "section data 0x{:x}+0x{:x} past end of section (0x{:x})",
+            offset,
+            len,
+            data.len()
+        )
+        .into()
+    })
+}
+
 fn be_u16(data: &[u8], offset: usize) -> Result<u16> {
-    Ok(u16::from_be_bytes(
-        data[offset..(offset + 2)]
-            .try_into()
-            .map_err(|e| anyhow!("conversion to u16 failed: {}", e))?,
-    ))
+    let bytes = section_slice(data, offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
 }
 
 fn be_u32(data: &[u8], offset: usize) -> Result<u32> {
-    Ok(u32::from_be_bytes(
-        data[offset..(offset + 4)]
-            .try_into()
-            .map_err(|e| anyhow!("conversion to u32 failed: {}", e))?,
-    ))
+    let bytes = section_slice(data, offset, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
 }
 
 #[cfg(test)]
@@ -299,6 +743,343 @@ mod tests {
             .unwrap();
         let mut reader = tokio::io::BufReader::new(reader);
         analysis.parse(&mut reader).await.unwrap();
-        println!("{:#?}", analysis);
+
+        let structure = analysis.structure.as_ref().unwrap();
+        assert!(structure.phrase_at(0).is_none());
+        let (phrase, remaining) = structure.phrase_at(1).unwrap();
+        assert_eq!((phrase.index, remaining), (1, 31));
+        let (phrase, remaining) = structure.phrase_at(40).unwrap();
+        assert_eq!((phrase.index, remaining), (2, 24));
+        assert_eq!(phrase.phrase_id.ty, PhraseType::Up);
+        let (phrase, remaining) = structure.phrase_at(607).unwrap();
+        assert_eq!((phrase.index, remaining), (16, 1));
+        assert_eq!(phrase.phrase_id.ty, PhraseType::Outro);
+        assert!(structure.phrase_at(608).is_none());
+        assert!(structure.phrase_at(0xffffffff).is_none());
+
+        let detail = analysis.waveforms.detail.as_ref().unwrap();
+        assert_eq!(detail.len(), 0xa9a5);
+        assert_eq!(
+            detail[5000],
+            MonochromeColumn {
+                height: 0x1e,
+                whiteness: 6,
+            }
+        );
+
+        let color_preview = analysis.waveforms.color_preview.as_ref().unwrap();
+        assert_eq!(color_preview.len(), 1200);
+        assert_eq!(
+            color_preview[60],
+            ColorPreviewColumn {
+                unknown: [0x62, 0xb0, 0x02],
+                red: 0x00,
+                green: 0x05,
+                blue: 0x32,
+            }
+        );
+
+        let color_detail = analysis.waveforms.color_detail.as_ref().unwrap();
+        assert_eq!(color_detail.len(), 0xa9a5);
+        assert_eq!(
+            color_detail[2000],
+            ColorDetailColumn {
+                red: 2,
+                green: 5,
+                blue: 5,
+                height: 15,
+            }
+        );
+
+        assert_eq!(analysis.cues.len(), 5);
+        assert_eq!(analysis.extended_cues.len(), 16);
+        assert_eq!(
+            analysis.cue_list()[0],
+            Cue {
+                hot_cue: 8,
+                ty: CueType::Point,
+                time: 0x2ca49,
+                loop_time: None,
+                comment: "Cue 8".to_string(),
+                color: Some([0x1a, 0xff, 0x00]),
+            }
+        );
+        assert_eq!(analysis.cue_list()[8].hot_cue, 0);
+        assert_eq!(analysis.cue_list()[8].color, None);
+
+        assert!(analysis.waveforms.preview.is_none());
+        assert!(analysis.waveforms.three_band_preview.is_none());
+        assert!(analysis.waveforms.three_band_detail.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dat_load() {
+        let mut analysis = Analysis::new();
+        let reader = tokio::fs::File::open("src/test-data/ANLZ0000.DAT")
+            .await
+            .unwrap();
+        let mut reader = tokio::io::BufReader::new(reader);
+        analysis.parse(&mut reader).await.unwrap();
+
+        let preview = analysis.waveforms.preview.as_ref().unwrap();
+        assert_eq!(preview.len(), 400);
+        assert_eq!(
+            preview[0],
+            MonochromeColumn {
+                height: 0x0b,
+                whiteness: 3,
+            }
+        );
+
+        let tiny_preview = analysis.waveforms.tiny_preview.as_ref().unwrap();
+        assert_eq!(tiny_preview.len(), 100);
+        assert_eq!(
+            tiny_preview[5],
+            MonochromeColumn {
+                height: 0x0f,
+                whiteness: 0,
+            }
+        );
+
+        let beat_grid = analysis.beat_grid.as_ref().unwrap();
+        assert_eq!(beat_grid.len(), 606);
+        assert_eq!(
+            beat_grid[3],
+            GridBeat {
+                beat_number: 1,
+                tempo: 12600,
+                time: 1904,
+            }
+        );
+
+        assert_eq!(analysis.cues.len(), 11);
+        assert_eq!(
+            analysis.cue_list()[2],
+            Cue {
+                hot_cue: 1,
+                ty: CueType::Point,
+                time: 475,
+                loop_time: None,
+                comment: "".to_string(),
+                color: None,
+            }
+        );
+        assert!(analysis.cues[3..].iter().all(|c| c.hot_cue == 0));
+
+        assert!(analysis.structure.is_none());
+        assert!(analysis.waveforms.detail.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let mut analysis = Analysis::new();
+        for path in &["src/test-data/ANLZ0000.DAT", "src/test-data/ANLZ0000.EXT"] {
+            let mut reader = tokio::fs::File::open(path).await.unwrap();
+            let mut parsed = Analysis::new();
+            parsed.parse(&mut reader).await.unwrap();
+            analysis.merge(parsed);
+        }
+
+        // Both files have a `PCOB` cue list.  Only the `.DAT` one is kept.
+        assert_eq!(analysis.cues.len(), 11);
+        assert_eq!(analysis.extended_cues.len(), 16);
+        assert_eq!(analysis.cue_list()[0].comment, "Cue 8");
+        assert_eq!(analysis.beat_grid.as_ref().unwrap().len(), 606);
+        assert!(analysis.structure.is_some());
+        assert_eq!(analysis.waveforms.preview.as_ref().unwrap().len(), 400);
+        assert_eq!(analysis.waveforms.detail.as_ref().unwrap().len(), 0xa9a5);
+    }
+
+    // PSSI section of ANLZ0000.EXT, which is masked.
+    fn song_structure_section() -> &'static [u8] {
+        &include_bytes!("test-data/ANLZ0000.EXT")[139751..(139751 + 416)]
+    }
+
+    #[test]
+    fn test_song_structure() {
+        let mut analysis = Analysis::new();
+        analysis
+            .parse_song_structure(song_structure_section())
+            .unwrap();
+        let structure = analysis.structure.unwrap();
+
+        assert_eq!(structure.mood, Mood::High);
+        assert_eq!(structure.end_beat, 608);
+        assert_eq!(structure.bank, Bank::Default);
+        assert_eq!(structure.phrases.len(), 16);
+        assert_eq!(
+            structure.phrases[1],
+            Phrase {
+                index: 2,
+                beats: vec![32],
+                phrase_id: PhraseId {
+                    ty: PhraseType::Up,
+                    index: 1,
+                },
+                fill_beats: 62,
+            }
+        );
+        assert_eq!(
+            structure.phrases[10],
+            Phrase {
+                index: 11,
+                beats: vec![352],
+                phrase_id: PhraseId {
+                    ty: PhraseType::Up,
+                    index: 3,
+                },
+                fill_beats: 380,
+            }
+        );
+        assert_eq!(
+            structure.phrases[15],
+            Phrase {
+                index: 16,
+                beats: vec![544],
+                phrase_id: PhraseId {
+                    ty: PhraseType::Outro,
+                    index: 1,
+                },
+                fill_beats: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_unmasked_song_structure() {
+        let masked = song_structure_section();
+        let mut masked_analysis = Analysis::new();
+        masked_analysis.parse_song_structure(masked).unwrap();
+
+        let mut unmasked = Analysis::mask_song_structure(masked, 16);
+        assert_eq!(&unmasked[0x12..0x14], &[0x00, 0x01]);
+        let mut unmasked_analysis = Analysis::new();
+        unmasked_analysis.parse_song_structure(&unmasked).unwrap();
+        assert_eq!(unmasked_analysis.structure, masked_analysis.structure);
+
+        // Warm bank and a phrase with extra beats.
+        unmasked[0x1e] = 5;
+        unmasked[0x20 + 0xb] = 0x01;
+        unmasked[(0x20 + 0xc)..(0x20 + 0x12)]
+            .copy_from_slice(&[0x00, 0x09, 0x00, 0x11, 0x00, 0x19]);
+        unmasked_analysis.parse_song_structure(&unmasked).unwrap();
+        let structure = unmasked_analysis.structure.take().unwrap();
+        assert_eq!(structure.bank, Bank::Warm);
+        assert_eq!(structure.phrases[0].beats, vec![1, 9, 17, 25]);
+
+        // Banks added by newer versions of rekordbox.
+        unmasked[0x1e] = 12;
+        unmasked_analysis.parse_song_structure(&unmasked).unwrap();
+        let structure = unmasked_analysis.structure.unwrap();
+        assert_eq!(structure.bank, Bank::Unknown(12));
+        assert_eq!(structure.phrases.len(), 16);
+    }
+
+    // Builds an ANLZ file out of raw `sections`.
+    fn anlz_file(sections: &[u8]) -> std::io::Cursor<Vec<u8>> {
+        let mut file = b"PMAI".to_vec();
+        file.extend_from_slice(&0x1cu32.to_be_bytes());
+        file.extend_from_slice(&((0x1c + sections.len()) as u32).to_be_bytes());
+        file.resize(0x1c, 0);
+        file.extend_from_slice(sections);
+        std::io::Cursor::new(file)
+    }
+
+    #[tokio::test]
+    async fn test_bad_section_lengths() {
+        // A zero length section would never advance.
+        let mut section = b"PQTZ".to_vec();
+        section.extend_from_slice(&[0, 0, 0, 0x18, 0, 0, 0, 0]);
+        section.resize(0x18, 0);
+        let mut file = anlz_file(&section);
+        assert!(Analysis::new().parse(&mut file).await.is_err());
+
+        // A section claiming to be longer than the file.
+        let mut section = b"PQTZ".to_vec();
+        section.extend_from_slice(&[0, 0, 0, 0x18, 0x7f, 0xff, 0xff, 0xff]);
+        section.resize(0x18, 0);
+        let mut file = anlz_file(&section);
+        assert!(Analysis::new().parse(&mut file).await.is_err());
+
+        // A file claiming to be longer than it is.
+        let mut file = anlz_file(&[]);
+        file.get_mut()[8..12].copy_from_slice(&0x7fff_ffffu32.to_be_bytes());
+        assert!(Analysis::new().parse(&mut file).await.is_err());
+
+        // An empty beat grid is fine.
+        let mut section = b"PQTZ".to_vec();
+        section.extend_from_slice(&[0, 0, 0, 0x18, 0, 0, 0, 0x18]);
+        section.resize(0x18, 0);
+        let mut file = anlz_file(&section);
+        let mut analysis = Analysis::new();
+        analysis.parse(&mut file).await.unwrap();
+        assert_eq!(analysis.beat_grid, Some(Vec::new()));
+    }
+
+    // Builds a `PCO2` section with one hot cue carrying `comment`.
+    fn extended_cue_list(comment: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0u8; 0x2c];
+        entry[0..4].copy_from_slice(b"PCP2");
+        entry[0x4..0x8].copy_from_slice(&0x10u32.to_be_bytes());
+        let entry_len = 0x2c + comment.len() + 4;
+        entry[0x8..0xc].copy_from_slice(&(entry_len as u32).to_be_bytes());
+        entry[0xc..0x10].copy_from_slice(&1u32.to_be_bytes());
+        entry[0x10] = 1;
+        entry[0x14..0x18].copy_from_slice(&1000u32.to_be_bytes());
+        entry[0x18..0x1c].copy_from_slice(&0xffffffffu32.to_be_bytes());
+        entry[0x28..0x2c].copy_from_slice(&(comment.len() as u32).to_be_bytes());
+        entry.extend_from_slice(comment);
+        entry.extend_from_slice(&[0x01, 0x10, 0x20, 0x30]);
+
+        let mut data = vec![0u8; 0x14];
+        data[0..4].copy_from_slice(b"PCO2");
+        data[0x4..0x8].copy_from_slice(&0x14u32.to_be_bytes());
+        data[0x8..0xc].copy_from_slice(&((0x14 + entry.len()) as u32).to_be_bytes());
+        data[0x10..0x12].copy_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&entry);
+        data
+    }
+
+    #[test]
+    fn test_extended_cue_comment() {
+        let cues = Analysis::parse_extended_cue_list(&extended_cue_list(&[0, b'A', 0, 0])).unwrap();
+        assert_eq!(cues.len(), 1);
+        assert_eq!(cues[0].hot_cue, 1);
+        assert_eq!(cues[0].time, 1000);
+        assert_eq!(cues[0].comment, "A");
+        assert_eq!(cues[0].color, Some([0x10, 0x20, 0x30]));
+
+        // A comment can't end halfway through a UTF-16 code unit.
+        assert!(Analysis::parse_extended_cue_list(&extended_cue_list(&[0, b'A', 0])).is_err());
+    }
+
+    #[test]
+    fn test_three_band() {
+        let data = [
+            b'P', b'W', b'V', b'6', 0x00, 0x00, 0x00, 0x14, /* PWV6.... */
+            0x00, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x00, 0x03, /* ........ */
+            0x00, 0x00, 0x00, 0x02, 0x10, 0x20, 0x30, 0x01, /* ..... 0. */
+            0x02, 0x03, /* .. */
+        ];
+        assert_eq!(
+            Analysis::parse_three_band(&data).unwrap(),
+            vec![
+                ThreeBandColumn {
+                    mid: 0x10,
+                    high: 0x20,
+                    low: 0x30,
+                },
+                ThreeBandColumn {
+                    mid: 0x01,
+                    high: 0x02,
+                    low: 0x03,
+                },
+            ]
+        );
+
+        // Truncated sections.
+        assert!(Analysis::parse_three_band(&data[..0x18]).is_err());
+        assert!(Analysis::parse_three_band(&data[..0x12]).is_err());
+        assert!(Analysis::parse_monochrome_preview(&data[..0x6]).is_err());
     }
 }
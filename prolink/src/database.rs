@@ -1,14 +1,19 @@
-use anyhow::anyhow;
-use log::{info, trace};
+use log::{info, trace, warn};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use pretty_hex::PrettyHex;
-use std::{collections::HashMap, convert::TryInto, io::SeekFrom};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    io::SeekFrom,
+};
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 
 use crate::Result;
 
-#[derive(Debug, Eq, FromPrimitive, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, FromPrimitive, Hash, PartialEq)]
 #[repr(u32)]
 pub enum TableType {
     Tracks = 0x00,
@@ -20,38 +25,81 @@ pub enum TableType {
     Colors = 0x06,
     PlaylistTree = 0x07,
     PlaylistEntries = 0x08,
+    HistoryPlaylists = 0x0b,
+    HistoryEntries = 0x0c,
     Artwork = 0x0d,
     Columns = 0x10,
-    HistoryPlaylists = 0x11,
-    HistoryEntires = 0x12,
     History = 0x13,
 }
 
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    #[error("read of {len} bytes at offset {offset:#x} is past the end of {size} bytes")]
+    OutOfBounds {
+        offset: usize,
+        len: usize,
+        size: usize,
+    },
+    #[error("invalid page size {0}")]
+    InvalidPageSize(usize),
+    #[error("page {0} appears twice in a table's page chain")]
+    PageLoop(u32),
+    #[error("invalid string: {0}")]
+    InvalidString(String),
+    #[error("unknown artist row subtype {0:#x}")]
+    UnknownArtistSubtype(u8),
+}
+
+// Table types found in exportExt.pdb.  These share the page layout of
+// export.pdb but number their tables independently.
+#[derive(Debug, Eq, FromPrimitive, Hash, PartialEq)]
+#[repr(u32)]
+pub enum ExtTableType {
+    Tags = 0x03,
+    TagTracks = 0x04,
+}
+
+pub(crate) const PAGE_HEADER_LEN: usize = 0x28;
+// Each group of 16 rows has 16 offsets, a valid mask and a flags word at the
+// end of the page.
+pub(crate) const ROW_GROUP_LEN: usize = 18 * 2;
+// rekordbox uses 4k pages.  Anything much larger is a corrupt header.
+const MAX_PAGE_SIZE: usize = 0x10000;
+
 #[derive(Debug)]
-pub struct TablePointer {
-    first_page: u32,
-    last_page: u32,
+pub(crate) enum Table {
+    Export(TableType),
+    Ext(ExtTableType),
 }
 
 #[derive(Debug)]
+pub struct TablePointer {
+    pub(crate) first_page: u32,
+    pub(crate) last_page: u32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
 pub struct RawAlbum {
     pub artist_id: u32,
     pub id: u32,
     pub name: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize)]
 pub struct RawTrack {
     pub sample_rate: u32,
     pub composer_id: u32,
+    // In bytes.
     pub file_size: u32,
     pub artwork_id: u32,
     pub key_id: u32,
     pub original_artist_id: u32,
     pub label_id: u32,
     pub remixer_id: u32,
+    // In kbps.
     pub bitrate: u32,
     pub track_number: u32,
+    // BPM * 100.
     pub tempo: u32,
     pub genre_id: u32,
     pub album_id: u32,
@@ -61,87 +109,220 @@ pub struct RawTrack {
     pub play_count: u16,
     pub year: u16,
     pub sample_depth: u16,
+    // In seconds.
     pub duration: u16,
     pub color_id: u8,
+    // 0-5 stars.
     pub rating: u8,
-    pub strings: Vec<String>,
+
+    pub isrc: String,
+    pub texter: String,
+    pub message: String,
+    // Whether the track is shared with KUVO.
+    pub kuvo_public: bool,
+    // Whether the players load the hot cues when the track is loaded.
+    pub autoload_hot_cues: bool,
+    // Dates are formatted as YYYY-MM-DD.
+    pub date_added: String,
+    pub release_date: String,
+    pub mix_name: String,
+    // Path of the track's `.DAT` analysis file on the media.
+    pub analyze_path: String,
+    pub analyze_date: String,
+    pub comment: String,
+    pub title: String,
+    pub filename: String,
+    // Path of the audio file on the media.
+    pub file_path: String,
 }
 
-#[derive(Debug)]
-pub struct Database {
-    page_size: usize,
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawPlaylist {
+    pub parent_id: u32,
+    pub sort_order: u32,
+    pub id: u32,
+    pub is_folder: bool,
+    pub name: String,
+}
 
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawPlaylistEntry {
+    pub entry_index: u32,
+    pub track_id: u32,
+    pub playlist_id: u32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawHistoryPlaylist {
+    pub id: u32,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawHistoryEntry {
+    pub track_id: u32,
+    pub playlist_id: u32,
+    pub entry_index: u32,
+}
+
+// A row of the history table.  Each records the YYYY-MM-DD date a track was
+// added to the play history and the name of the device that exported it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawHistory {
+    pub track_id: u32,
+    pub date: String,
+    pub device_name: String,
+}
+
+// A history session recorded by a player.  Tracks can be looked up with
+// `Database::history_tracks`.  `date` is the latest history table date of
+// the session's tracks.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistorySession {
+    pub id: u32,
+    pub name: String,
+    pub date: Option<String>,
+}
+
+// A node in the playlist tree.  Folders have children, playlists have
+// entries that can be looked up with `Database::playlist_tracks`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Playlist {
+    pub id: u32,
+    pub name: String,
+    pub is_folder: bool,
+    pub children: Vec<Playlist>,
+}
+
+// An entry of the player's browse menu.  `number` identifies the category
+// the entry browses by.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RawColumn {
+    pub id: u16,
+    pub number: u16,
+    pub name: String,
+}
+
+// A My Tag or My Tag category from exportExt.pdb.  Tags belong to the
+// category with ID `category_id`, categories have a `category_id` of 0.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawTag {
+    pub id: u32,
+    pub category_id: u32,
+    pub category_pos: u32,
+    pub is_category: bool,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RawTagTrack {
+    pub track_id: u32,
+    pub tag_id: u32,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Database {
     pub albums: HashMap<u32, RawAlbum>,
     pub artists: HashMap<u32, String>,
     pub artwork: HashMap<u32, String>,
     pub colors: HashMap<u32, String>,
+    pub columns: HashMap<u16, RawColumn>,
     pub generes: HashMap<u32, String>,
+    pub history: HashMap<u32, RawHistory>,
+    pub history_entries: HashMap<u32, Vec<RawHistoryEntry>>,
+    pub history_playlists: HashMap<u32, RawHistoryPlaylist>,
     pub keys: HashMap<u32, String>,
     pub labels: HashMap<u32, String>,
+    pub playlists: HashMap<u32, RawPlaylist>,
+    pub playlist_entries: HashMap<u32, Vec<RawPlaylistEntry>>,
+    pub tags: HashMap<u32, RawTag>,
+    pub tag_tracks: HashMap<u32, Vec<RawTagTrack>>,
     pub tracks: HashMap<u32, RawTrack>,
 }
 
 impl Database {
     #[allow(dead_code)]
     pub async fn parse<R: AsyncRead + AsyncSeek + Unpin>(r: &mut R) -> Result<Database> {
+        let mut db = Database::default();
+        let (page_size, table_pointers) = Self::read_table_pointers(r).await?;
+
+        for (raw_table_type, table_ptr) in &table_pointers {
+            match FromPrimitive::from_u32(*raw_table_type) {
+                Some(t) => {
+                    db.read_table(r, page_size, &Table::Export(t), table_ptr)
+                        .await?
+                }
+                None => info!(target: "database", "unknown table type: {}", raw_table_type),
+            }
+        }
+        Ok(db)
+    }
+
+    // Merges the tables of an exportExt.pdb file into this database.
+    pub async fn parse_ext<R: AsyncRead + AsyncSeek + Unpin>(&mut self, r: &mut R) -> Result<()> {
+        let (page_size, table_pointers) = Self::read_table_pointers(r).await?;
+
+        for (raw_table_type, table_ptr) in &table_pointers {
+            match FromPrimitive::from_u32(*raw_table_type) {
+                Some(t) => {
+                    self.read_table(r, page_size, &Table::Ext(t), table_ptr)
+                        .await?
+                }
+                None => info!(target: "database", "unknown ext table type: {}", raw_table_type),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn read_table_pointers<R: AsyncRead + AsyncSeek + Unpin>(
+        r: &mut R,
+    ) -> Result<(usize, Vec<(u32, TablePointer)>)> {
         r.seek(SeekFrom::Start(4)).await?;
         let page_size = r.read_u32_le().await? as usize;
+        if !(PAGE_HEADER_LEN + ROW_GROUP_LEN..=MAX_PAGE_SIZE).contains(&page_size) {
+            return Err(DatabaseError::InvalidPageSize(page_size).into());
+        }
         let num_tables = r.read_u32_le().await?;
         r.seek(SeekFrom::Current(8)).await?;
         let _sequence = r.read_u32_le().await?;
         r.seek(SeekFrom::Current(4)).await?;
 
-        let mut table_pointers = HashMap::new();
+        let mut table_pointers = Vec::new();
 
         for _ in 0..num_tables {
             let raw_table_type = r.read_u32_le().await?;
-            let table_type: Option<TableType> = FromPrimitive::from_u32(raw_table_type);
             r.seek(SeekFrom::Current(4)).await?;
             let first_page = r.read_u32_le().await?;
             let last_page = r.read_u32_le().await?;
 
-            if let Some(t) = table_type {
-                table_pointers.insert(
-                    t,
-                    TablePointer {
-                        first_page,
-                        last_page,
-                    },
-                );
-            } else {
-                info!(target: "database", "unknown table type: {}", raw_table_type);
-            }
+            table_pointers.push((
+                raw_table_type,
+                TablePointer {
+                    first_page,
+                    last_page,
+                },
+            ));
         }
 
-        let mut db = Database {
-            page_size,
-            albums: HashMap::new(),
-            artists: HashMap::new(),
-            artwork: HashMap::new(),
-            colors: HashMap::new(),
-            generes: HashMap::new(),
-            keys: HashMap::new(),
-            labels: HashMap::new(),
-            tracks: HashMap::new(),
-        };
-
-        for (table_type, table_ptr) in &table_pointers {
-            db.read_table(r, table_type, table_ptr).await?;
-        }
-        Ok(db)
+        Ok((page_size, table_pointers))
     }
 
     async fn read_table<R: AsyncRead + AsyncSeek + Unpin>(
         &mut self,
         r: &mut R,
-        table_type: &TableType,
+        page_size: usize,
+        table_type: &Table,
         table_ptr: &TablePointer,
     ) -> Result<()> {
         let mut cur_page = table_ptr.first_page;
-        let mut page_data = vec![0; self.page_size];
+        let mut page_data = vec![0; page_size];
+        let mut visited = HashSet::new();
 
         loop {
-            r.seek(SeekFrom::Start(cur_page as u64 * self.page_size as u64))
+            if !visited.insert(cur_page) {
+                return Err(DatabaseError::PageLoop(cur_page).into());
+            }
+            r.seek(SeekFrom::Start(cur_page as u64 * page_size as u64))
                 .await?;
             r.read_exact(&mut page_data).await?;
 
@@ -156,17 +337,17 @@ impl Database {
         Ok(())
     }
 
-    fn parse_page(&mut self, table_type: &TableType, page_data: &Vec<u8>) -> Result<u32> {
+    pub(crate) fn parse_page(&mut self, table_type: &Table, page_data: &[u8]) -> Result<u32> {
         trace!("{:?}", page_data.hex_dump());
         let next_page = le_u32(page_data, 0xc)?;
 
-        let page_flags = page_data[0x1b];
+        let page_flags = u8_at(page_data, 0x1b)?;
         if (page_flags & 0x40) != 0 {
             trace!("strange page");
             return Ok(next_page);
         }
 
-        let num_rows_small = page_data[0x18];
+        let num_rows_small = u8_at(page_data, 0x18)?;
         let num_rows_large = le_u16(page_data, 0x22)?;
         let num_rows = if num_rows_large == 0x1fff {
             num_rows_small as usize
@@ -178,7 +359,21 @@ impl Database {
         for i in 0..num_rows {
             let group = i / 16;
             let sub_index = i % 16;
-            let group_offset = page_data.len() - group * 18 * 2;
+
+            // The row index grows down from the end of the page.  A row count
+            // that runs it into the page header means the page is damaged.
+            let group_offset = match page_data.len().checked_sub(group * ROW_GROUP_LEN) {
+                Some(offset) if offset >= PAGE_HEADER_LEN + ROW_GROUP_LEN => offset,
+                _ => {
+                    warn!(
+                    target: "database",
+                    "page {} claims {} rows, skipping the rest",
+                    le_u32(page_data, 0x4)?,
+                        num_rows
+                    );
+                    break;
+                }
+            };
             let valid_mask = le_u16(page_data, group_offset - 4)?;
             trace!(
                 "parsing row {} {} {} {} {:x}",
@@ -195,27 +390,49 @@ impl Database {
                 continue;
             }
 
-            let row_offset = 0x28 + le_u16(page_data, group_offset - 6 - 2 * sub_index)? as usize;
+            let row_offset =
+                PAGE_HEADER_LEN + le_u16(page_data, group_offset - 6 - 2 * sub_index)? as usize;
             trace!("row_offset {}", row_offset);
 
-            self.parse_row(table_type, &page_data[row_offset..])?;
+            let result =
+                tail(page_data, row_offset).and_then(|row| self.parse_row(table_type, row));
+            if let Err(e) = result {
+                warn!(
+                    target: "database",
+                    "skipping damaged {:?} row {} on page {}: {}",
+                    table_type,
+                    i,
+                    le_u32(page_data, 0x4)?,
+                    e
+                );
+            }
         }
 
         Ok(next_page)
     }
 
-    fn parse_row(&mut self, table_type: &TableType, row_data: &[u8]) -> Result<()> {
+    fn parse_row(&mut self, table_type: &Table, row_data: &[u8]) -> Result<()> {
         trace!("parse row");
+        let table_type = match table_type {
+            Table::Export(t) => t,
+            Table::Ext(ExtTableType::Tags) => return self.parse_tag_row(row_data),
+            Table::Ext(ExtTableType::TagTracks) => return self.parse_tag_track_row(row_data),
+        };
         match table_type {
             TableType::Albums => self.parse_album_row(row_data),
             TableType::Artists => self.parse_artist_row(row_data),
             TableType::Artwork => self.parse_artwork_row(row_data),
             TableType::Colors => self.parse_color_row(row_data),
+            TableType::Columns => self.parse_column_row(row_data),
             TableType::Generes => self.parse_genere_row(row_data),
+            TableType::History => self.parse_history_row(row_data),
+            TableType::HistoryEntries => self.parse_history_entry_row(row_data),
+            TableType::HistoryPlaylists => self.parse_history_playlist_row(row_data),
             TableType::Keys => self.parse_key_row(row_data),
             TableType::Labels => self.parse_label_row(row_data),
+            TableType::PlaylistTree => self.parse_playlist_tree_row(row_data),
+            TableType::PlaylistEntries => self.parse_playlist_entry_row(row_data),
             TableType::Tracks => self.parse_track_row(row_data),
-            _ => Ok(()), // Silently ignore unsupported rows types.
         }
     }
 
@@ -223,8 +440,8 @@ impl Database {
         trace!("parse album row");
         let artist_id = le_u32(row_data, 0x8)?;
         let id = le_u32(row_data, 0xc)?;
-        let offset = row_data[0x15] as usize;
-        let name = Self::parse_string(&row_data[offset..])?;
+        let offset = u8_at(row_data, 0x15)? as usize;
+        let name = Self::parse_string(tail(row_data, offset)?)?;
 
         self.albums.insert(
             id,
@@ -240,15 +457,15 @@ impl Database {
 
     fn parse_artist_row(&mut self, row_data: &[u8]) -> Result<()> {
         trace!("parse artist row");
-        let sub_type = row_data[0];
+        let sub_type = u8_at(row_data, 0)?;
         let id = le_u32(row_data, 4)?;
         let offset = match sub_type {
-            0x60 => row_data[0x9] as usize,
+            0x60 => u8_at(row_data, 0x9)? as usize,
             0x64 => le_u32(row_data, 0xa)? as usize,
-            _ => return Err(anyhow!("Unknown artist record subtype {:x}", sub_type).into()),
+            _ => return Err(DatabaseError::UnknownArtistSubtype(sub_type).into()),
         };
 
-        let name = Self::parse_string(&row_data[offset..])?;
+        let name = Self::parse_string(tail(row_data, offset)?)?;
 
         self.artists.insert(id, name);
 
@@ -258,7 +475,7 @@ impl Database {
     fn parse_artwork_row(&mut self, row_data: &[u8]) -> Result<()> {
         trace!("parse artwork row");
         let id = le_u32(row_data, 0x0)?;
-        let string = Self::parse_string(&row_data[4..])?;
+        let string = Self::parse_string(tail(row_data, 4)?)?;
         self.artwork.insert(id, string);
 
         Ok(())
@@ -267,25 +484,88 @@ impl Database {
     fn parse_color_row(&mut self, row_data: &[u8]) -> Result<()> {
         trace!("parse color row");
         let id = le_u16(row_data, 0x5)? as u32;
-        let string = Self::parse_string(&row_data[8..])?;
+        let string = Self::parse_string(tail(row_data, 8)?)?;
         self.colors.insert(id, string);
 
         Ok(())
     }
 
+    fn parse_column_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse column row");
+        let id = le_u16(row_data, 0x0)?;
+        let number = le_u16(row_data, 0x2)?;
+        let name = Self::parse_string(tail(row_data, 0x4)?)?;
+
+        // Names are wrapped in U+FFFA and U+FFFB markers.
+        let name = name
+            .trim_start_matches('\u{fffa}')
+            .trim_end_matches('\u{fffb}')
+            .to_string();
+
+        self.columns.insert(id, RawColumn { id, number, name });
+
+        Ok(())
+    }
+
     fn parse_genere_row(&mut self, row_data: &[u8]) -> Result<()> {
         trace!("parse genere row");
         let id = le_u32(row_data, 0x0)? as u32;
-        let string = Self::parse_string(&row_data[4..])?;
+        let string = Self::parse_string(tail(row_data, 4)?)?;
         self.generes.insert(id, string);
 
         Ok(())
     }
 
+    fn parse_history_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse history row");
+        let track_id = le_u32(row_data, 0x4)?;
+        let date = Self::parse_string(tail(row_data, 0xc)?)?;
+        let device_name_offset = u8_at(row_data, 0x18)? as usize;
+        let device_name = Self::parse_string(tail(row_data, device_name_offset)?)?;
+        self.history.insert(
+            track_id,
+            RawHistory {
+                track_id,
+                date,
+                device_name,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn parse_history_entry_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse history entry row");
+        let track_id = le_u32(row_data, 0x0)?;
+        let playlist_id = le_u32(row_data, 0x4)?;
+        let entry_index = le_u32(row_data, 0x8)?;
+
+        self.history_entries
+            .entry(playlist_id)
+            .or_default()
+            .push(RawHistoryEntry {
+                track_id,
+                playlist_id,
+                entry_index,
+            });
+
+        Ok(())
+    }
+
+    fn parse_history_playlist_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse history playlist row");
+        let id = le_u32(row_data, 0x0)?;
+        let name = Self::parse_string(tail(row_data, 4)?)?;
+        self.history_playlists
+            .insert(id, RawHistoryPlaylist { id, name });
+
+        Ok(())
+    }
+
     fn parse_key_row(&mut self, row_data: &[u8]) -> Result<()> {
         trace!("parse key row");
         let id = le_u32(row_data, 0x0)? as u32;
-        let string = Self::parse_string(&row_data[8..])?;
+        let string = Self::parse_string(tail(row_data, 8)?)?;
         self.keys.insert(id, string);
 
         Ok(())
@@ -294,12 +574,52 @@ impl Database {
     fn parse_label_row(&mut self, row_data: &[u8]) -> Result<()> {
         trace!("parse lable row");
         let id = le_u32(row_data, 0x0)? as u32;
-        let string = Self::parse_string(&row_data[4..])?;
+        let string = Self::parse_string(tail(row_data, 4)?)?;
         self.labels.insert(id, string);
 
         Ok(())
     }
 
+    fn parse_playlist_tree_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse playlist tree row");
+        let parent_id = le_u32(row_data, 0x0)?;
+        let sort_order = le_u32(row_data, 0x8)?;
+        let id = le_u32(row_data, 0xc)?;
+        let is_folder = le_u32(row_data, 0x10)? != 0;
+        let name = Self::parse_string(tail(row_data, 0x14)?)?;
+
+        self.playlists.insert(
+            id,
+            RawPlaylist {
+                parent_id,
+                sort_order,
+                id,
+                is_folder,
+                name,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn parse_playlist_entry_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse playlist entry row");
+        let entry_index = le_u32(row_data, 0x0)?;
+        let track_id = le_u32(row_data, 0x4)?;
+        let playlist_id = le_u32(row_data, 0x8)?;
+
+        self.playlist_entries
+            .entry(playlist_id)
+            .or_default()
+            .push(RawPlaylistEntry {
+                entry_index,
+                track_id,
+                playlist_id,
+            });
+
+        Ok(())
+    }
+
     fn parse_track_row(&mut self, row_data: &[u8]) -> Result<()> {
         trace!("parse track row");
         let sample_rate = le_u32(row_data, 0x08)?;
@@ -318,19 +638,20 @@ impl Database {
         let artist_id = le_u32(row_data, 0x44)?;
         let id = le_u32(row_data, 0x48)?;
         let disc = le_u16(row_data, 0x4c)?;
-        let play_count = le_u16(row_data, 0x4c)?;
+        let play_count = le_u16(row_data, 0x4e)?;
         let year = le_u16(row_data, 0x50)?;
         let sample_depth = le_u16(row_data, 0x52)?;
         let duration = le_u16(row_data, 0x54)?;
-        let color_id = row_data[0x58];
-        let rating = row_data[0x59];
+        let color_id = u8_at(row_data, 0x58)?;
+        let rating = u8_at(row_data, 0x59)?;
 
         let mut strings = Vec::new();
-        for i in 0..20 {
+        for i in 0..21 {
             let offset = le_u16(row_data, 0x5e + 2 * i)? as usize;
-            let string = Self::parse_string(&row_data[offset..])?;
+            let string = Self::parse_string(tail(row_data, offset)?)?;
             strings.push(string);
         }
+        let mut string = |i: usize| std::mem::take(&mut strings[i]);
 
         self.tracks.insert(
             id,
@@ -357,15 +678,188 @@ impl Database {
                 duration,
                 color_id,
                 rating,
-                strings,
+                isrc: string(0),
+                texter: string(1),
+                message: string(5),
+                kuvo_public: string(6) == "ON",
+                autoload_hot_cues: string(7) == "ON",
+                date_added: string(10),
+                release_date: string(11),
+                mix_name: string(12),
+                analyze_path: string(14),
+                analyze_date: string(15),
+                comment: string(16),
+                title: string(17),
+                filename: string(19),
+                file_path: string(20),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn parse_tag_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse tag row");
+        let category_id = le_u32(row_data, 0xc)?;
+        let category_pos = le_u32(row_data, 0x10)?;
+        let id = le_u32(row_data, 0x14)?;
+        let is_category = le_u32(row_data, 0x18)? != 0;
+        let offset = u8_at(row_data, 0x1d)? as usize;
+        let name = Self::parse_string(tail(row_data, offset)?)?;
+
+        self.tags.insert(
+            id,
+            RawTag {
+                id,
+                category_id,
+                category_pos,
+                is_category,
+                name,
             },
         );
 
         Ok(())
     }
 
+    fn parse_tag_track_row(&mut self, row_data: &[u8]) -> Result<()> {
+        trace!("parse tag track row");
+        let track_id = le_u32(row_data, 0x4)?;
+        let tag_id = le_u32(row_data, 0x8)?;
+
+        self.tag_tracks
+            .entry(track_id)
+            .or_default()
+            .push(RawTagTrack { track_id, tag_id });
+
+        Ok(())
+    }
+
+    // Returns the browse menu entries in the order the players show them.
+    pub fn columns(&self) -> Vec<&RawColumn> {
+        let mut columns: Vec<&RawColumn> = self.columns.values().collect();
+        columns.sort_by_key(|c| c.id);
+        columns
+    }
+
+    // Returns the playlist tree with each level in the order the players show
+    // it.  The root level has a parent ID of 0.
+    pub fn playlists(&self) -> Vec<Playlist> {
+        self.playlist_children(&mut vec![0])
+    }
+
+    // `path` holds the IDs of the folders from the root down to the one being
+    // listed.  Damaged rows can make a folder its own ancestor, so rows that
+    // are already on the path are skipped.
+    fn playlist_children(&self, path: &mut Vec<u32>) -> Vec<Playlist> {
+        let parent_id = path[path.len() - 1];
+        let mut children: Vec<&RawPlaylist> = self
+            .playlists
+            .values()
+            .filter(|p| p.parent_id == parent_id && !path.contains(&p.id))
+            .collect();
+        children.sort_by_key(|p| (p.sort_order, p.id));
+
+        children
+            .iter()
+            .map(|p| Playlist {
+                id: p.id,
+                name: p.name.clone(),
+                is_folder: p.is_folder,
+                children: if p.is_folder {
+                    path.push(p.id);
+                    let children = self.playlist_children(path);
+                    path.pop();
+                    children
+                } else {
+                    Vec::new()
+                },
+            })
+            .collect()
+    }
+
+    // Returns the tracks of playlist `id` in playlist order.  Entries pointing
+    // at tracks that are not in the database are skipped.
+    pub fn playlist_tracks(&self, id: u32) -> Vec<&RawTrack> {
+        let mut entries: Vec<&RawPlaylistEntry> = match self.playlist_entries.get(&id) {
+            Some(entries) => entries.iter().collect(),
+            None => return Vec::new(),
+        };
+        entries.sort_by_key(|e| e.entry_index);
+
+        entries
+            .iter()
+            .filter_map(|e| self.tracks.get(&e.track_id))
+            .collect()
+    }
+
+    // Returns the history sessions in the order they were recorded.
+    pub fn history_sessions(&self) -> Vec<HistorySession> {
+        let mut sessions: Vec<HistorySession> = self
+            .history_playlists
+            .values()
+            .map(|p| HistorySession {
+                id: p.id,
+                name: p.name.clone(),
+                date: self.session_date(p.id),
+            })
+            .collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    // Sessions are dated by the history table rows of their tracks.  A
+    // session none of whose tracks has a row falls back to the latest date
+    // in the table, which is when the history was last exported.
+    fn session_date(&self, id: u32) -> Option<String> {
+        let entries = self.history_entries.get(&id)?;
+        entries
+            .iter()
+            .filter_map(|e| self.history.get(&e.track_id))
+            .map(|h| &h.date)
+            .max()
+            .or_else(|| self.history.values().map(|h| &h.date).max())
+            .cloned()
+    }
+
+    // Returns the tracks of history session `id` in the order they were
+    // played.
+    pub fn history_tracks(&self, id: u32) -> Vec<&RawTrack> {
+        let mut entries: Vec<&RawHistoryEntry> = match self.history_entries.get(&id) {
+            Some(entries) => entries.iter().collect(),
+            None => return Vec::new(),
+        };
+        entries.sort_by_key(|e| e.entry_index);
+
+        entries
+            .iter()
+            .filter_map(|e| self.tracks.get(&e.track_id))
+            .collect()
+    }
+
+    // Returns the My Tags assigned to track `id`, ordered by category and
+    // then by their position within the category.
+    pub fn track_tags(&self, id: u32) -> Vec<&RawTag> {
+        let mut tags: Vec<&RawTag> = match self.tag_tracks.get(&id) {
+            Some(entries) => entries
+                .iter()
+                .filter_map(|e| self.tags.get(&e.tag_id))
+                .filter(|t| !t.is_category)
+                .collect(),
+            None => return Vec::new(),
+        };
+        tags.sort_by_key(|t| {
+            let category_pos = self
+                .tags
+                .get(&t.category_id)
+                .map(|c| c.category_pos)
+                .unwrap_or(u32::MAX);
+            (category_pos, t.category_pos, t.id)
+        });
+        tags
+    }
+
     fn parse_string(data: &[u8]) -> Result<String> {
-        let flags = data[0];
+        let flags = u8_at(data, 0)?;
 
         if flags == 0x90 && data.len() > 4 && data[4] == 0x3 {
             // ISRC string
@@ -373,10 +867,20 @@ impl Database {
             if len < 6 {
                 return Ok("".to_string());
             }
-            let str_data = &data[5..(len - 1)];
+            let str_data = range(data, 5, len - 1)?;
             trace!("ISRC parsing utf8 {:x?}", str_data);
             Ok(String::from_utf8(str_data.into())
-                .map_err(|e| anyhow!("Error converting ASCII string: {}", e))?)
+                .map_err(|e| DatabaseError::InvalidString(e.to_string()))?)
+        } else if flags == 0x40 {
+            // Long ASCII string
+            let len = le_u16(data, 1)? as usize;
+            if len < 5 {
+                return Ok("".to_string());
+            }
+            let str_data = range(data, 4, len)?;
+            trace!("long parsing utf8 {:x?}", str_data);
+            Ok(String::from_utf8(str_data.into())
+                .map_err(|e| DatabaseError::InvalidString(e.to_string()))?)
         } else if (flags & 0x1) == 0 {
             let len = le_u16(data, 1)? as usize;
 
@@ -385,55 +889,393 @@ impl Database {
             }
 
             if len % 2 != 0 {
-                return Err(anyhow!("utf16 string not a mulitple of 2 ({})", len).into());
+                return Err(DatabaseError::InvalidString(format!(
+                    "utf16 string not a mulitple of 2 ({})",
+                    len
+                ))
+                .into());
             }
-            let string: Vec<u16> = data[4..len]
+            let string: Vec<u16> = range(data, 4, len)?
                 .chunks(2)
                 .map(|b| (b[0] as u16) + ((b[1] as u16) << 8))
                 .collect();
             Ok(String::from_utf16(&string)
-                .map_err(|e| anyhow!("Error converting UTF16 string: {}", e))?)
+                .map_err(|e| DatabaseError::InvalidString(e.to_string()))?)
         } else {
             let len = (flags >> 1) as usize;
             if len < 2 {
                 return Ok("".to_string());
             }
-            let str_data = &data[1..len];
+            let str_data = range(data, 1, len)?;
             trace!("parsing utf8 {:x?}", str_data);
             Ok(String::from_utf8(str_data.into())
-                .map_err(|e| anyhow!("Error converting ASCII string: {}", e))?)
+                .map_err(|e| DatabaseError::InvalidString(e.to_string()))?)
         }
     }
 }
 
+fn range(data: &[u8], start: usize, end: usize) -> Result<&[u8]> {
+    data.get(start..end).ok_or_else(|| {
+        DatabaseError::OutOfBounds {
+            offset: start,
+            len: end.saturating_sub(start),
+            size: data.len(),
+        }
+        .into()
+    })
+}
+
+fn tail(data: &[u8], offset: usize) -> Result<&[u8]> {
+    range(data, offset, data.len().max(offset))
+}
+
+fn u8_at(data: &[u8], offset: usize) -> Result<u8> {
+    Ok(range(data, offset, offset + 1)?[0])
+}
+
 fn le_u16(data: &[u8], offset: usize) -> Result<u16> {
     Ok(u16::from_le_bytes(
-        data[offset..(offset + 2)]
-            .try_into()
-            .map_err(|e| anyhow!("conversion to u16 failed: {}", e))?,
+        range(data, offset, offset + 2)?.try_into().unwrap(),
     ))
 }
 
 fn le_u32(data: &[u8], offset: usize) -> Result<u32> {
     Ok(u32::from_le_bytes(
-        data[offset..(offset + 4)]
-            .try_into()
-            .map_err(|e| anyhow!("conversion to u32 failed: {}", e))?,
+        range(data, offset, offset + 4)?.try_into().unwrap(),
     ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database_writer::build_file;
+    use crate::test_util::{load_test_database, TEST_DATABASE};
 
     #[tokio::test]
     async fn test_database_load() {
         let _ = env_logger::builder().is_test(true).try_init();
-        let reader = tokio::fs::File::open("src/test-data/export.pdb")
-            .await
-            .unwrap();
-        let mut reader = tokio::io::BufReader::new(reader);
-        let db = Database::parse(&mut reader).await.unwrap();
-        println!("{:#?}", db);
+        let db = load_test_database().await;
+        assert_eq!(db.tracks.len(), 455);
+        assert_eq!(db.artists.len(), 278);
+        assert_eq!(db.albums.len(), 189);
+        assert_eq!(db.keys.len(), 24);
+        assert_eq!(db.generes.len(), 22);
+        assert_eq!(db.playlists.len(), 15);
+        let entries: usize = db.playlist_entries.values().map(|e| e.len()).sum();
+        assert_eq!(entries, 778);
+    }
+
+    #[tokio::test]
+    async fn test_playlists() {
+        let db = load_test_database().await;
+        let playlists = db.playlists();
+
+        let names: Vec<&str> = playlists.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Old House",
+                "Swing House",
+                "Techno",
+                "FRC - basshouse",
+                "FRC - dayparty",
+                "New House"
+            ]
+        );
+
+        let techno = &playlists[2];
+        assert!(techno.is_folder);
+        let names: Vec<&str> = techno.children.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Latin Tribal",
+                "Techno 2022-05-20",
+                "Driving",
+                "Medium",
+                "All"
+            ]
+        );
+        assert!(playlists[0].children.is_empty());
+
+        let new_house = &playlists[5];
+        assert_eq!(new_house.children[0].id, 15);
+        let tracks = db.playlist_tracks(15);
+        assert_eq!(tracks.len(), db.playlist_entries[&15].len());
+        assert_eq!(tracks[0].id, 424);
+        assert_eq!(tracks[1].id, 425);
+
+        assert!(db.playlist_tracks(11).is_empty());
+    }
+
+    #[test]
+    fn test_playlist_cycles() {
+        let mut db = Database::default();
+        let mut add = |parent_id, id, is_folder| {
+            db.playlists.insert(
+                id,
+                RawPlaylist {
+                    parent_id,
+                    sort_order: id,
+                    id,
+                    is_folder,
+                    name: format!("playlist {}", id),
+                },
+            );
+        };
+        add(0, 1, true);
+        add(1, 2, true);
+        // A damaged row that would make the root level a child of folder 2.
+        add(2, 0, true);
+        add(2, 3, false);
+
+        let playlists = db.playlists();
+        assert_eq!(playlists.len(), 1);
+        let folder = &playlists[0].children[0];
+        assert_eq!(folder.id, 2);
+        let ids: Vec<u32> = folder.children.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_tracks() {
+        let db = load_test_database().await;
+
+        let track = &db.tracks[&1];
+        assert_eq!(track.sample_rate, 44100);
+        assert_eq!(track.file_size, 54099896);
+        assert_eq!(track.disc, 0);
+        assert_eq!(track.play_count, 1);
+        assert_eq!(track.sample_depth, 16);
+        assert_eq!(track.duration, 306);
+        assert_eq!(track.isrc, "");
+        assert!(!track.kuvo_public);
+        assert!(track.autoload_hot_cues);
+        assert_eq!(track.date_added, "2021-08-12");
+        assert_eq!(track.release_date, "");
+        assert_eq!(
+            track.analyze_path,
+            "/PIONEER/USBANLZ/P034/00007F42/ANLZ0000.DAT"
+        );
+        assert_eq!(track.analyze_date, "2021-10-09");
+        assert_eq!(track.title, "Tribal Battlefield (Original Mix)");
+        assert_eq!(
+            track.filename,
+            "4069304_FTek_Tribal Battlefield_Original Mi.aiff"
+        );
+        assert_eq!(
+            track.file_path,
+            "/Contents/FTek/UnknownAlbum/4069304_FTek_Tribal Battlefield_Original Mi.aiff"
+        );
+
+        assert_eq!(db.tracks[&43].isrc, "GBKQU1710050");
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let db = load_test_database().await;
+        let sessions = db.history_sessions();
+
+        assert_eq!(
+            sessions,
+            vec![
+                HistorySession {
+                    id: 1,
+                    name: "HISTORY 001".to_string(),
+                    date: Some("2022-05-21".to_string()),
+                },
+                HistorySession {
+                    id: 2,
+                    name: "HISTORY 002".to_string(),
+                    date: Some("2022-05-21".to_string()),
+                },
+            ]
+        );
+
+        let ids: Vec<u32> = db.history_tracks(1).iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![437, 450, 449, 451]);
+        let ids: Vec<u32> = db.history_tracks(2).iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![425]);
+        assert_eq!(
+            db.history[&455],
+            RawHistory {
+                track_id: 455,
+                date: "2022-05-21".to_string(),
+                device_name: "Konkers".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_dates() {
+        let mut db = load_test_database().await;
+        for (track_id, date) in [
+            (437, "2022-06-01"),
+            (450, "2022-06-02"),
+            (425, "2022-06-03"),
+        ] {
+            db.history.insert(
+                track_id,
+                RawHistory {
+                    track_id,
+                    date: date.to_string(),
+                    device_name: "CDJ-3000".to_string(),
+                },
+            );
+        }
+
+        // Each session takes the latest date of its own tracks.
+        let dates: Vec<Option<String>> =
+            db.history_sessions().into_iter().map(|s| s.date).collect();
+        assert_eq!(
+            dates,
+            vec![
+                Some("2022-06-02".to_string()),
+                Some("2022-06-03".to_string())
+            ]
+        );
+
+        db.history.clear();
+        assert!(db.history_sessions().iter().all(|s| s.date.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_columns() {
+        let db = load_test_database().await;
+        let columns = db.columns();
+
+        let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(&names[..5], &["GENRE", "ARTIST", "ALBUM", "TRACK", "BPM"]);
+        assert_eq!(
+            columns[0],
+            &RawColumn {
+                id: 1,
+                number: 0x80,
+                name: "GENRE".to_string(),
+            }
+        );
+        let playlist = columns.iter().find(|c| c.name == "PLAYLIST").unwrap();
+        assert_eq!(playlist.number, 0x84);
+    }
+
+    fn tag(id: u32, category_id: u32, category_pos: u32, name: &str) -> RawTag {
+        RawTag {
+            id,
+            category_id,
+            category_pos,
+            is_category: category_id == 0,
+            name: name.to_string(),
+        }
+    }
+
+    // A database holding just `tags` and the `(track, tag)` pairs in
+    // `tag_tracks`.
+    fn tag_database(tags: Vec<RawTag>, tag_tracks: &[(u32, u32)]) -> Database {
+        let mut db = Database::default();
+        for tag in tags {
+            db.tags.insert(tag.id, tag);
+        }
+        for &(track_id, tag_id) in tag_tracks {
+            db.tag_tracks
+                .entry(track_id)
+                .or_default()
+                .push(RawTagTrack { track_id, tag_id });
+        }
+        db
+    }
+
+    #[tokio::test]
+    async fn test_ext_tags() {
+        let mut db = load_test_database().await;
+        let tagged = tag_database(
+            vec![
+                tag(1, 0, 1, "Genre"),
+                tag(2, 0, 0, "Energy"),
+                tag(10, 1, 0, "Disco"),
+                tag(20, 2, 1, "Peak"),
+                tag(21, 2, 0, "Warmup"),
+            ],
+            &[(1, 10), (1, 20), (1, 21), (2, 21)],
+        );
+        // Ext table numbers overlap export.pdb's, 0x7 being the playlist tree
+        // there.
+        let ext = build_file(&[
+            (ExtTableType::Tags as u32, tagged.tag_rows().unwrap()),
+            (ExtTableType::TagTracks as u32, tagged.tag_track_rows()),
+            (0x7, vec![]),
+        ])
+        .unwrap();
+        db.parse_ext(&mut std::io::Cursor::new(ext)).await.unwrap();
+
+        assert_eq!(db.tags.len(), 5);
+        assert!(db.tags[&2].is_category);
+        assert!(!db.tags[&20].is_category);
+        assert_eq!(db.tags[&20].category_id, 2);
+
+        let names =
+            |id| -> Vec<String> { db.track_tags(id).iter().map(|t| t.name.clone()).collect() };
+        assert_eq!(names(1), vec!["Warmup", "Peak", "Disco"]);
+        assert_eq!(names(2), vec!["Warmup"]);
+        assert!(names(3).is_empty());
+
+        // The export.pdb tables are left untouched.
+        assert_eq!(db.tracks.len(), load_test_database().await.tracks.len());
+    }
+
+    #[tokio::test]
+    async fn test_damaged_rows_are_skipped() {
+        let tagged = tag_database(
+            vec![
+                tag(1, 0, 0, "Genre"),
+                tag(2, 0, 1, "Mood"),
+                tag(3, 0, 2, "Broken"),
+            ],
+            &[],
+        );
+        let mut rows = tagged.tag_rows().unwrap();
+        // A UTF-16 name that claims to run past the end of the page.
+        rows[2][0x1f..0x22].copy_from_slice(&[0x90, 0xfe, 0xff]);
+        let ext = build_file(&[(ExtTableType::Tags as u32, rows)]).unwrap();
+
+        let mut db = Database::default();
+        db.parse_ext(&mut std::io::Cursor::new(ext)).await.unwrap();
+        assert_eq!(db.tags.len(), 2);
+        assert!(!db.tags.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_database() {
+        let data = std::fs::read(TEST_DATABASE).unwrap();
+
+        // Truncated files fail with an error instead of panicking.
+        for len in [0, 3, 0x1c, 4096, 4096 + 0x20, data.len() / 2] {
+            let mut r = std::io::Cursor::new(data[..len].to_vec());
+            assert!(Database::parse(&mut r).await.is_err());
+        }
+
+        // So do files with random bytes overwritten.  A simple LCG keeps the
+        // test deterministic.
+        let mut seed = 0x2545_f491_u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            seed
+        };
+        for _ in 0..32 {
+            let mut corrupt = data.clone();
+            for _ in 0..64 {
+                let offset = next() as usize % corrupt.len();
+                corrupt[offset] = next() as u8;
+            }
+            let _ = Database::parse(&mut std::io::Cursor::new(corrupt)).await;
+        }
+
+        // A page chain that loops back on itself is detected.
+        let mut looped = build_file(&[(TableType::Generes as u32, vec![])]).unwrap();
+        looped[4096 + 0x0c..4096 + 0x10].copy_from_slice(&1u32.to_le_bytes());
+        looped[0x1c + 12..0x1c + 16].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(
+            Database::parse(&mut std::io::Cursor::new(looped)).await,
+            Err(crate::ProlinkError::Database(DatabaseError::PageLoop(1)))
+        ));
     }
 }
@@ -0,0 +1,717 @@
+use anyhow::anyhow;
+use std::collections::HashMap;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    database::{Database, ExtTableType, RawTrack, TableType, PAGE_HEADER_LEN, ROW_GROUP_LEN},
+    Result,
+};
+
+const PAGE_SIZE: usize = 4096;
+const ROWS_PER_GROUP: usize = 16;
+
+const PAGE_FLAGS_DATA: u8 = 0x24;
+// Data pages listed in their table's index.
+const PAGE_FLAGS_INDEXED_DATA: u8 = 0x34;
+const PAGE_FLAGS_HEADER: u8 = 0x64;
+
+// Header pages hold an index with room for this many entries.
+const INDEX_ENTRIES: usize = 0x3ec;
+const INDEX_EMPTY_ENTRY: u32 = 0x1fff_fff8;
+
+impl Database {
+    // Writes the export.pdb tables of this database.  My Tags live in
+    // exportExt.pdb and are written by `write_ext`.
+    pub async fn write<W: AsyncWrite + Unpin>(&self, w: &mut W) -> Result<()> {
+        let tables = vec![
+            (TableType::Tracks as u32, self.track_rows()?),
+            (TableType::Generes as u32, id_name_rows(&self.generes, 0x4)),
+            (TableType::Artists as u32, self.artist_rows()),
+            (TableType::Albums as u32, self.album_rows()),
+            (TableType::Labels as u32, id_name_rows(&self.labels, 0x4)),
+            (TableType::Keys as u32, self.key_rows()),
+            (TableType::Colors as u32, self.color_rows()),
+            (TableType::PlaylistTree as u32, self.playlist_tree_rows()),
+            (
+                TableType::PlaylistEntries as u32,
+                self.playlist_entry_rows(),
+            ),
+            (
+                TableType::HistoryPlaylists as u32,
+                self.history_playlist_rows(),
+            ),
+            (TableType::HistoryEntries as u32, self.history_entry_rows()),
+            (TableType::Artwork as u32, id_name_rows(&self.artwork, 0x4)),
+            (TableType::Columns as u32, self.column_rows()),
+            (TableType::History as u32, self.history_rows()?),
+        ];
+
+        w.write_all(&build_file(&tables)?).await?;
+        Ok(())
+    }
+
+    // Writes the exportExt.pdb tables of this database.
+    pub async fn write_ext<W: AsyncWrite + Unpin>(&self, w: &mut W) -> Result<()> {
+        let tables = vec![
+            (ExtTableType::Tags as u32, self.tag_rows()?),
+            (ExtTableType::TagTracks as u32, self.tag_track_rows()),
+        ];
+
+        w.write_all(&build_file(&tables)?).await?;
+        Ok(())
+    }
+
+    fn track_rows(&self) -> Result<Vec<Vec<u8>>> {
+        sorted(&self.tracks).map(|(_, t)| track_row(t)).collect()
+    }
+
+    fn artist_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.artists)
+            .map(|(id, name)| {
+                let mut row = vec![0u8; 0xa];
+                put_u16(&mut row, 0x0, 0x60);
+                put_u32(&mut row, 0x4, *id);
+                row[0x8] = 0x03;
+                row[0x9] = 0xa;
+                row.extend(encode_string(name));
+                row
+            })
+            .collect()
+    }
+
+    fn album_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.albums)
+            .map(|(_, album)| {
+                let mut row = vec![0u8; 0x16];
+                put_u16(&mut row, 0x0, 0x80);
+                put_u32(&mut row, 0x8, album.artist_id);
+                put_u32(&mut row, 0xc, album.id);
+                row[0x14] = 0x03;
+                row[0x15] = 0x16;
+                row.extend(encode_string(&album.name));
+                row
+            })
+            .collect()
+    }
+
+    fn key_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.keys)
+            .map(|(id, name)| {
+                let mut row = vec![0u8; 0x8];
+                put_u32(&mut row, 0x0, *id);
+                put_u32(&mut row, 0x4, *id);
+                row.extend(encode_string(name));
+                row
+            })
+            .collect()
+    }
+
+    fn color_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.colors)
+            .map(|(id, name)| {
+                let mut row = vec![0u8; 0x8];
+                put_u16(&mut row, 0x5, *id as u16);
+                row.extend(encode_string(name));
+                row
+            })
+            .collect()
+    }
+
+    fn playlist_tree_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.playlists)
+            .map(|(_, playlist)| {
+                let mut row = vec![0u8; 0x14];
+                put_u32(&mut row, 0x0, playlist.parent_id);
+                put_u32(&mut row, 0x8, playlist.sort_order);
+                put_u32(&mut row, 0xc, playlist.id);
+                put_u32(&mut row, 0x10, playlist.is_folder as u32);
+                row.extend(encode_string(&playlist.name));
+                row
+            })
+            .collect()
+    }
+
+    fn playlist_entry_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.playlist_entries)
+            .flat_map(|(_, entries)| entries.iter())
+            .map(|entry| {
+                let mut row = vec![0u8; 0xc];
+                put_u32(&mut row, 0x0, entry.entry_index);
+                put_u32(&mut row, 0x4, entry.track_id);
+                put_u32(&mut row, 0x8, entry.playlist_id);
+                row
+            })
+            .collect()
+    }
+
+    fn history_playlist_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.history_playlists)
+            .map(|(_, playlist)| {
+                let mut row = vec![0u8; 0x4];
+                put_u32(&mut row, 0x0, playlist.id);
+                row.extend(encode_string(&playlist.name));
+                row
+            })
+            .collect()
+    }
+
+    fn history_entry_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.history_entries)
+            .flat_map(|(_, entries)| entries.iter())
+            .map(|entry| {
+                let mut row = vec![0u8; 0xc];
+                put_u32(&mut row, 0x0, entry.track_id);
+                put_u32(&mut row, 0x4, entry.playlist_id);
+                put_u32(&mut row, 0x8, entry.entry_index);
+                row
+            })
+            .collect()
+    }
+
+    fn column_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.columns)
+            .map(|(_, column)| {
+                let mut row = vec![0u8; 0x4];
+                put_u16(&mut row, 0x0, column.id);
+                put_u16(&mut row, 0x2, column.number);
+                row.extend(encode_utf16_string(&format!(
+                    "\u{fffa}{}\u{fffb}",
+                    column.name
+                )));
+                row
+            })
+            .collect()
+    }
+
+    // The string offsets sit right after the date, so dates have to be
+    // YYYY-MM-DD like rekordbox writes them.
+    fn history_rows(&self) -> Result<Vec<Vec<u8>>> {
+        sorted(&self.history)
+            .map(|(_, history)| {
+                let date = encode_string(&history.date);
+                if date.len() != 0xb {
+                    return Err(anyhow!("history date {:?} isn't YYYY-MM-DD", history.date).into());
+                }
+                let version = encode_string("1000");
+                let mut row = vec![0u8; 0xc];
+                put_u16(&mut row, 0x0, 0x0280);
+                put_u32(&mut row, 0x4, history.track_id);
+                row.extend(date);
+                row.push(0x19);
+                row.push((0x19 + version.len()) as u8);
+                row.extend(version);
+                row.extend(encode_string(&history.device_name));
+                Ok(row)
+            })
+            .collect()
+    }
+
+    pub(crate) fn tag_rows(&self) -> Result<Vec<Vec<u8>>> {
+        sorted(&self.tags)
+            .map(|(_, tag)| {
+                let name = encode_string(&tag.name);
+                // The offset of the string after the name is a single byte.
+                let end = 0x1f + name.len();
+                if end > 0xff {
+                    return Err(anyhow!("tag name {:?} is too long", tag.name).into());
+                }
+                let mut row = vec![0u8; 0x1f];
+                put_u16(&mut row, 0x0, 0x0680);
+                put_u32(&mut row, 0xc, tag.category_id);
+                put_u32(&mut row, 0x10, tag.category_pos);
+                put_u32(&mut row, 0x14, tag.id);
+                put_u32(&mut row, 0x18, tag.is_category as u32);
+                row[0x1c] = 0x03;
+                row[0x1d] = 0x1f;
+                row[0x1e] = end as u8;
+                row.extend(name);
+                row.extend(encode_string(""));
+                Ok(row)
+            })
+            .collect()
+    }
+
+    pub(crate) fn tag_track_rows(&self) -> Vec<Vec<u8>> {
+        sorted(&self.tag_tracks)
+            .flat_map(|(_, entries)| entries.iter())
+            .map(|entry| {
+                let mut row = vec![0u8; 0x10];
+                put_u32(&mut row, 0x4, entry.track_id);
+                put_u32(&mut row, 0x8, entry.tag_id);
+                put_u32(&mut row, 0xc, 0x3);
+                row
+            })
+            .collect()
+    }
+}
+
+fn track_row(track: &RawTrack) -> Result<Vec<u8>> {
+    let on_off = |on: bool| if on { "ON" } else { "" };
+    let strings = [
+        "",
+        &track.texter,
+        "",
+        "",
+        "",
+        &track.message,
+        on_off(track.kuvo_public),
+        on_off(track.autoload_hot_cues),
+        "",
+        "",
+        &track.date_added,
+        &track.release_date,
+        &track.mix_name,
+        "",
+        &track.analyze_path,
+        &track.analyze_date,
+        &track.comment,
+        &track.title,
+        "",
+        &track.filename,
+        &track.file_path,
+    ];
+
+    let mut row = vec![0u8; 0x88];
+    put_u16(&mut row, 0x00, 0x24);
+    put_u32(&mut row, 0x08, track.sample_rate);
+    put_u32(&mut row, 0x0c, track.composer_id);
+    put_u32(&mut row, 0x10, track.file_size);
+    put_u32(&mut row, 0x1c, track.artwork_id);
+    put_u32(&mut row, 0x20, track.key_id);
+    put_u32(&mut row, 0x24, track.original_artist_id);
+    put_u32(&mut row, 0x28, track.label_id);
+    put_u32(&mut row, 0x2c, track.remixer_id);
+    put_u32(&mut row, 0x30, track.bitrate);
+    put_u32(&mut row, 0x34, track.track_number);
+    put_u32(&mut row, 0x38, track.tempo);
+    put_u32(&mut row, 0x3c, track.genre_id);
+    put_u32(&mut row, 0x40, track.album_id);
+    put_u32(&mut row, 0x44, track.artist_id);
+    put_u32(&mut row, 0x48, track.id);
+    put_u16(&mut row, 0x4c, track.disc);
+    put_u16(&mut row, 0x4e, track.play_count);
+    put_u16(&mut row, 0x50, track.year);
+    put_u16(&mut row, 0x52, track.sample_depth);
+    put_u16(&mut row, 0x54, track.duration);
+    put_u16(&mut row, 0x56, 0x29);
+    row[0x58] = track.color_id;
+    row[0x59] = track.rating;
+    put_u16(&mut row, 0x5a, 0x1);
+    put_u16(&mut row, 0x5c, 0x3);
+
+    for (i, string) in strings.iter().enumerate() {
+        let offset = row.len();
+        if offset > u16::MAX as usize {
+            return Err(anyhow!("track {} row too large", track.id).into());
+        }
+        put_u16(&mut row, 0x5e + 2 * i, offset as u16);
+        if i == 0 && !track.isrc.is_empty() {
+            row.extend(encode_isrc_string(&track.isrc));
+        } else {
+            row.extend(encode_string(string));
+        }
+    }
+
+    Ok(row)
+}
+
+// Rows made of a u32 ID followed by a name at `name_offset`.
+fn id_name_rows(table: &HashMap<u32, String>, name_offset: usize) -> Vec<Vec<u8>> {
+    sorted(table)
+        .map(|(id, name)| {
+            let mut row = vec![0u8; name_offset];
+            put_u32(&mut row, 0x0, *id);
+            row.extend(encode_string(name));
+            row
+        })
+        .collect()
+}
+
+// Iterates over a table in ID order so the output is deterministic.
+fn sorted<K: Ord, V>(table: &HashMap<K, V>) -> impl Iterator<Item = (&K, &V)> + '_ {
+    let mut entries: Vec<(&K, &V)> = table.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries.into_iter()
+}
+
+// Encodes `s` in the most compact of the encodings `Database::parse_string`
+// understands.
+fn encode_string(s: &str) -> Vec<u8> {
+    if !s.is_ascii() {
+        return encode_utf16_string(s);
+    }
+
+    let len = s.len() + 1;
+    if len < 0x80 {
+        let mut data = vec![((len << 1) | 1) as u8];
+        data.extend(s.as_bytes());
+        data
+    } else {
+        let len = s.len() + 4;
+        let mut data = vec![0x40, len as u8, (len >> 8) as u8, 0x00];
+        data.extend(s.as_bytes());
+        data
+    }
+}
+
+fn encode_utf16_string(s: &str) -> Vec<u8> {
+    let chars: Vec<u16> = s.encode_utf16().collect();
+    let len = chars.len() * 2 + 4;
+    let mut data = vec![0x90, len as u8, (len >> 8) as u8, 0x00];
+    for c in chars {
+        data.extend(&c.to_le_bytes());
+    }
+    data
+}
+
+fn encode_isrc_string(s: &str) -> Vec<u8> {
+    let len = s.len() + 6;
+    let mut data = vec![0x90, len as u8, (len >> 8) as u8, 0x00, 0x03];
+    data.extend(s.as_bytes());
+    data.push(0x00);
+    data
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn row_index_len(num_rows: usize) -> usize {
+    num_rows.div_ceil(ROWS_PER_GROUP) * ROW_GROUP_LEN
+}
+
+// Splits `rows` into the sets of rows that fit on a page.
+fn paginate(rows: &[Vec<u8>]) -> Result<Vec<&[Vec<u8>]>> {
+    let heap_len = PAGE_SIZE - PAGE_HEADER_LEN;
+    let mut pages = Vec::new();
+    let mut start = 0;
+    let mut heap_used = 0;
+
+    for (i, row) in rows.iter().enumerate() {
+        if row.len() + row_index_len(1) > heap_len {
+            return Err(anyhow!("row of {} bytes does not fit in a page", row.len()).into());
+        }
+
+        let num_rows = i - start + 1;
+        if align4(heap_used) + row.len() + row_index_len(num_rows) > heap_len {
+            pages.push(&rows[start..i]);
+            start = i;
+            heap_used = 0;
+        }
+        heap_used = align4(heap_used) + row.len();
+    }
+    if start < rows.len() {
+        pages.push(&rows[start..]);
+    }
+
+    Ok(pages)
+}
+
+fn write_page_header(page: &mut [u8], page_index: u32, table_type: u32, next_page: u32, flags: u8) {
+    put_u32(page, 0x04, page_index);
+    put_u32(page, 0x08, table_type);
+    put_u32(page, 0x0c, next_page);
+    put_u32(page, 0x10, 0x1);
+    page[0x1b] = flags;
+}
+
+// Writes the index rekordbox keeps on a table's header page.  Each entry is
+// the number of one of the table's data pages shifted left by three, and the
+// data pages listed are flagged `PAGE_FLAGS_INDEXED_DATA`.
+fn write_index_page(
+    page: &mut [u8],
+    page_index: u32,
+    next_page: u32,
+    data_pages: &[u32],
+) -> Result<()> {
+    if data_pages.len() > INDEX_ENTRIES {
+        return Err(anyhow!("{} data pages don't fit in a table index", data_pages.len()).into());
+    }
+
+    put_u16(page, 0x20, 0x1fff);
+    put_u16(page, 0x22, 0x1fff);
+    put_u16(page, 0x24, INDEX_ENTRIES as u16);
+    put_u16(page, 0x26, data_pages.len() as u16);
+    put_u32(page, 0x28, page_index);
+    put_u32(page, 0x2c, next_page);
+    put_u32(page, 0x30, 0x03ff_ffff);
+    put_u16(page, 0x38, data_pages.len() as u16);
+    put_u16(page, 0x3a, 0x1fff);
+    for i in 0..INDEX_ENTRIES {
+        let entry = data_pages.get(i).map_or(INDEX_EMPTY_ENTRY, |p| p << 3);
+        put_u32(page, 0x3c + 4 * i, entry);
+    }
+    Ok(())
+}
+
+fn write_data_page(page: &mut [u8], rows: &[Vec<u8>]) {
+    let mut heap_used = 0;
+    for (i, row) in rows.iter().enumerate() {
+        let offset = align4(heap_used);
+        let start = PAGE_HEADER_LEN + offset;
+        page[start..start + row.len()].copy_from_slice(row);
+        heap_used = offset + row.len();
+
+        let group_offset = PAGE_SIZE - (i / ROWS_PER_GROUP) * ROW_GROUP_LEN;
+        let sub_index = i % ROWS_PER_GROUP;
+        put_u16(page, group_offset - 6 - 2 * sub_index, offset as u16);
+
+        let mask_offset = group_offset - 4;
+        let mask = u16::from_le_bytes([page[mask_offset], page[mask_offset + 1]]);
+        put_u16(page, mask_offset, mask | (1 << sub_index));
+    }
+
+    let num_rows = rows.len();
+    let free = PAGE_SIZE - PAGE_HEADER_LEN - heap_used - row_index_len(num_rows);
+    page[0x18] = num_rows as u8;
+    put_u16(page, 0x1c, free as u16);
+    put_u16(page, 0x1e, heap_used as u16);
+    put_u16(page, 0x20, 0x1);
+    put_u16(page, 0x22, num_rows as u16);
+}
+
+// Lays out `tables` as a database file.  Each table gets a header page
+// followed by its data pages and an empty page the players can allocate
+// from.
+pub(crate) fn build_file(tables: &[(u32, Vec<Vec<u8>>)]) -> Result<Vec<u8>> {
+    let mut pages: Vec<Vec<u8>> = vec![vec![0u8; PAGE_SIZE]];
+    let mut pointers = Vec::new();
+
+    for (table_type, rows) in tables {
+        let row_pages = paginate(rows)?;
+        let first_page = pages.len() as u32;
+        let empty_candidate = first_page + row_pages.len() as u32 + 1;
+
+        let mut header = vec![0u8; PAGE_SIZE];
+        let next_page = if row_pages.is_empty() {
+            empty_candidate
+        } else {
+            first_page + 1
+        };
+        write_page_header(
+            &mut header,
+            first_page,
+            *table_type,
+            next_page,
+            PAGE_FLAGS_HEADER,
+        );
+        let data_pages: Vec<u32> = (0..row_pages.len() as u32)
+            .map(|i| first_page + 1 + i)
+            .collect();
+        write_index_page(&mut header, first_page, next_page, &data_pages)?;
+        pages.push(header);
+
+        for (i, page_rows) in row_pages.iter().enumerate() {
+            let page_index = pages.len() as u32;
+            let next_page = if i + 1 == row_pages.len() {
+                empty_candidate
+            } else {
+                page_index + 1
+            };
+            let mut page = vec![0u8; PAGE_SIZE];
+            write_page_header(
+                &mut page,
+                page_index,
+                *table_type,
+                next_page,
+                PAGE_FLAGS_INDEXED_DATA,
+            );
+            write_data_page(&mut page, page_rows);
+            pages.push(page);
+        }
+        let last_page = pages.len() as u32 - 1;
+
+        let mut empty = vec![0u8; PAGE_SIZE];
+        write_page_header(&mut empty, empty_candidate, *table_type, 0, PAGE_FLAGS_DATA);
+        write_data_page(&mut empty, &[]);
+        pages.push(empty);
+
+        pointers.push((*table_type, empty_candidate, first_page, last_page));
+    }
+
+    let num_pages = pages.len() as u32;
+    let header = &mut pages[0];
+    put_u32(header, 0x04, PAGE_SIZE as u32);
+    put_u32(header, 0x08, tables.len() as u32);
+    put_u32(header, 0x0c, num_pages);
+    put_u32(header, 0x10, 0x5);
+    put_u32(header, 0x14, 0x1);
+    for (i, (table_type, empty_candidate, first_page, last_page)) in pointers.iter().enumerate() {
+        let offset = 0x1c + i * 16;
+        put_u32(header, offset, *table_type);
+        put_u32(header, offset + 4, *empty_candidate);
+        put_u32(header, offset + 8, *first_page);
+        put_u32(header, offset + 12, *last_page);
+    }
+
+    Ok(pages.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{RawTag, RawTagTrack};
+    use crate::test_util::{load_test_database, TEST_DATABASE};
+    use std::io::Cursor;
+
+    async fn round_trip(db: &Database) -> Database {
+        let mut data = Vec::new();
+        db.write(&mut data).await.unwrap();
+        assert_eq!(data.len() % PAGE_SIZE, 0);
+        Database::parse(&mut Cursor::new(data)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let db = load_test_database().await;
+        let written = round_trip(&db).await;
+        assert_eq!(written, db);
+
+        assert_eq!(written.tracks[&43].isrc, "GBKQU1710050");
+        assert_eq!(written.playlists(), db.playlists());
+        assert_eq!(written.columns(), db.columns());
+    }
+
+    #[test]
+    fn test_index_pages() {
+        let fixture = std::fs::read(TEST_DATABASE).unwrap();
+        let fixture_page = |i: usize| &fixture[i * PAGE_SIZE..(i + 1) * PAGE_SIZE];
+
+        // The genre table header is an empty index on page 3 with its data
+        // starting on page 4.
+        let mut page = vec![0u8; PAGE_SIZE];
+        write_page_header(
+            &mut page,
+            3,
+            TableType::Generes as u32,
+            4,
+            PAGE_FLAGS_HEADER,
+        );
+        write_index_page(&mut page, 3, 4, &[]).unwrap();
+        assert_eq!(page, fixture_page(3));
+
+        // The history table indexes its data pages 40 and 41.  The header's
+        // sequence number at 0x10 isn't something the writer tracks.
+        assert_eq!(fixture_page(40)[0x1b], PAGE_FLAGS_INDEXED_DATA);
+        let mut page = vec![0u8; PAGE_SIZE];
+        write_page_header(
+            &mut page,
+            39,
+            TableType::History as u32,
+            40,
+            PAGE_FLAGS_HEADER,
+        );
+        page[0x10..0x14].copy_from_slice(&fixture_page(39)[0x10..0x14]);
+        write_index_page(&mut page, 39, 40, &[40, 41]).unwrap();
+        assert_eq!(page, fixture_page(39));
+
+        assert!(write_index_page(&mut page, 39, 40, &[40; INDEX_ENTRIES + 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_long_tag_name() {
+        let mut db = Database::default();
+        db.tags.insert(
+            1,
+            RawTag {
+                id: 1,
+                category_id: 0,
+                category_pos: 0,
+                is_category: true,
+                name: "x".repeat(0xdc),
+            },
+        );
+        let mut data = Vec::new();
+        db.write_ext(&mut data).await.unwrap();
+        let mut written = Database::default();
+        written.parse_ext(&mut Cursor::new(data)).await.unwrap();
+        assert_eq!(written, db);
+
+        // One more byte and the offset of the string after the name no
+        // longer fits.
+        db.tags.get_mut(&1).unwrap().name = "x".repeat(0xdd);
+        let mut data = Vec::new();
+        assert!(db.write_ext(&mut data).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_string_encodings() {
+        let mut db = Database::default();
+        let names = [
+            "".to_string(),
+            "short".to_string(),
+            "a".repeat(126),
+            "a".repeat(127),
+            "long ".repeat(100),
+            "Ünïcødé ナイト".to_string(),
+        ];
+        for (i, name) in names.iter().enumerate() {
+            db.artists.insert(i as u32 + 1, name.clone());
+        }
+
+        let written = round_trip(&db).await;
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(&written.artists[&(i as u32 + 1)], name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_many_rows() {
+        // Enough rows to span several pages and row groups.
+        let mut db = Database::default();
+        for id in 1..2000 {
+            db.generes.insert(id, format!("Genre {}", id));
+        }
+
+        let written = round_trip(&db).await;
+        assert_eq!(written, db);
+    }
+
+    #[tokio::test]
+    async fn test_ext_round_trip() {
+        let mut db = Database::default();
+        db.tags.insert(
+            1,
+            RawTag {
+                id: 1,
+                category_id: 0,
+                category_pos: 0,
+                is_category: true,
+                name: "Energy".to_string(),
+            },
+        );
+        db.tags.insert(
+            2,
+            RawTag {
+                id: 2,
+                category_id: 1,
+                category_pos: 0,
+                is_category: false,
+                name: "Peak".to_string(),
+            },
+        );
+        db.tag_tracks.insert(
+            7,
+            vec![RawTagTrack {
+                track_id: 7,
+                tag_id: 2,
+            }],
+        );
+
+        let mut data = Vec::new();
+        db.write_ext(&mut data).await.unwrap();
+        let mut written = Database::default();
+        written.parse_ext(&mut Cursor::new(data)).await.unwrap();
+
+        assert_eq!(written, db);
+        assert_eq!(written.track_tags(7)[0].name, "Peak");
+    }
+}
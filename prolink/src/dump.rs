@@ -0,0 +1,298 @@
+use serde::Serialize;
+use std::io::Write;
+
+use crate::{
+    database::{Database, Playlist},
+    Result, TrackMetadata,
+};
+
+// A track with its IDs resolved to names.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackRecord {
+    pub id: u32,
+    #[serde(flatten)]
+    pub metadata: TrackMetadata,
+    pub file_path: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlaylistRecord {
+    pub id: u32,
+    pub name: String,
+    pub is_folder: bool,
+    // Tracks in playlist order.  Folders have no tracks.
+    pub track_ids: Vec<u32>,
+    pub children: Vec<PlaylistRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistoryRecord {
+    pub id: u32,
+    pub name: String,
+    pub date: Option<String>,
+    // Tracks in the order they were played.
+    pub track_ids: Vec<u32>,
+}
+
+// A self contained snapshot of a collection suitable for archiving.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LibraryDump {
+    pub tracks: Vec<TrackRecord>,
+    pub playlists: Vec<PlaylistRecord>,
+    pub history: Vec<HistoryRecord>,
+}
+
+const TRACK_COLUMNS: &[&str] = &[
+    "id",
+    "title",
+    "artist",
+    "album",
+    "album_artist",
+    "genre",
+    "key",
+    "label",
+    "composer",
+    "original_artist",
+    "remixer",
+    "mix_name",
+    "tempo",
+    "duration",
+    "year",
+    "rating",
+    "color",
+    "track_number",
+    "disc",
+    "play_count",
+    "bitrate",
+    "sample_rate",
+    "sample_depth",
+    "file_size",
+    "isrc",
+    "date_added",
+    "release_date",
+    "comment",
+    "tags",
+    "file_path",
+];
+
+const PLAYLIST_COLUMNS: &[&str] = &[
+    "playlist_id",
+    "playlist",
+    "position",
+    "track_id",
+    "title",
+    "artist",
+];
+
+impl LibraryDump {
+    pub fn new(db: &Database) -> LibraryDump {
+        let mut tracks: Vec<TrackRecord> = db
+            .tracks
+            .values()
+            .map(|t| TrackRecord {
+                id: t.id,
+                metadata: TrackMetadata::from_track(db, t),
+                file_path: t.file_path.clone(),
+            })
+            .collect();
+        tracks.sort_by_key(|t| t.id);
+
+        let playlists = db
+            .playlists()
+            .iter()
+            .map(|p| Self::playlist_record(db, p))
+            .collect();
+
+        let history = db
+            .history_sessions()
+            .into_iter()
+            .map(|s| HistoryRecord {
+                track_ids: db.history_tracks(s.id).iter().map(|t| t.id).collect(),
+                id: s.id,
+                name: s.name,
+                date: s.date,
+            })
+            .collect();
+
+        LibraryDump {
+            tracks,
+            playlists,
+            history,
+        }
+    }
+
+    fn playlist_record(db: &Database, playlist: &Playlist) -> PlaylistRecord {
+        PlaylistRecord {
+            id: playlist.id,
+            name: playlist.name.clone(),
+            is_folder: playlist.is_folder,
+            track_ids: db
+                .playlist_tracks(playlist.id)
+                .iter()
+                .map(|t| t.id)
+                .collect(),
+            children: playlist
+                .children
+                .iter()
+                .map(|p| Self::playlist_record(db, p))
+                .collect(),
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, w: W) -> Result<()> {
+        serde_json::to_writer_pretty(w, self)?;
+        Ok(())
+    }
+
+    // Writes one row per track.  My Tags are joined with "; ".
+    pub fn write_tracks_csv<W: Write>(&self, w: W) -> Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+        writer.write_record(TRACK_COLUMNS)?;
+
+        for track in &self.tracks {
+            let m = &track.metadata;
+            writer.write_record(&[
+                track.id.to_string(),
+                m.title.clone(),
+                m.artist.clone(),
+                m.album_name.clone(),
+                m.album_artist.clone(),
+                m.genre.clone(),
+                m.key.clone(),
+                m.label.clone(),
+                m.composer.clone(),
+                m.original_artist.clone(),
+                m.remixer.clone(),
+                m.mix_name.clone(),
+                m.tempo.to_string(),
+                m.duration.to_string(),
+                m.year.to_string(),
+                m.rating.to_string(),
+                m.color.clone(),
+                m.track_number.to_string(),
+                m.disc.to_string(),
+                m.play_count.to_string(),
+                m.bitrate.to_string(),
+                m.sample_rate.to_string(),
+                m.sample_depth.to_string(),
+                m.file_size.to_string(),
+                m.isrc.clone(),
+                m.date_added.clone(),
+                m.release_date.clone(),
+                m.comment.clone(),
+                m.tags.join("; "),
+                track.file_path.clone(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Writes one row per playlist entry.  Playlists are named by their path
+    // in the playlist tree, e.g. "Folder / Playlist".
+    pub fn write_playlists_csv<W: Write>(&self, w: W) -> Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+        writer.write_record(PLAYLIST_COLUMNS)?;
+        for playlist in &self.playlists {
+            self.write_playlist_rows(&mut writer, playlist, "")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_playlist_rows<W: Write>(
+        &self,
+        writer: &mut csv::Writer<W>,
+        playlist: &PlaylistRecord,
+        parent: &str,
+    ) -> Result<()> {
+        let path = if parent.is_empty() {
+            playlist.name.clone()
+        } else {
+            format!("{} / {}", parent, playlist.name)
+        };
+
+        for (position, track_id) in playlist.track_ids.iter().enumerate() {
+            let (title, artist) = match self.tracks.binary_search_by_key(track_id, |t| t.id) {
+                Ok(i) => (
+                    self.tracks[i].metadata.title.as_str(),
+                    self.tracks[i].metadata.artist.as_str(),
+                ),
+                Err(_) => ("", ""),
+            };
+            writer.write_record(&[
+                playlist.id.to_string(),
+                path.clone(),
+                (position + 1).to_string(),
+                track_id.to_string(),
+                title.to_string(),
+                artist.to_string(),
+            ])?;
+        }
+
+        for child in &playlist.children {
+            self.write_playlist_rows(writer, child, &path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::load_test_database;
+
+    async fn load_test_dump() -> LibraryDump {
+        LibraryDump::new(&load_test_database().await)
+    }
+
+    #[tokio::test]
+    async fn test_json_dump() {
+        let dump = load_test_dump().await;
+        let mut data = Vec::new();
+        dump.write_json(&mut data).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        let tracks = value["tracks"].as_array().unwrap();
+        assert_eq!(tracks.len(), dump.tracks.len());
+
+        let track = tracks.iter().find(|t| t["id"] == 1).unwrap();
+        assert_eq!(track["title"], "Tribal Battlefield (Original Mix)");
+        assert_eq!(
+            track["file_path"],
+            "/Contents/FTek/UnknownAlbum/4069304_FTek_Tribal Battlefield_Original Mi.aiff"
+        );
+        assert!(track["artist"].is_string());
+
+        assert_eq!(value["playlists"][0]["name"], "Old House");
+    }
+
+    #[tokio::test]
+    async fn test_csv_dump() {
+        let dump = load_test_dump().await;
+
+        let mut data = Vec::new();
+        dump.write_tracks_csv(&mut data).unwrap();
+        let mut reader = csv::Reader::from_reader(data.as_slice());
+        assert_eq!(reader.headers().unwrap(), TRACK_COLUMNS);
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), dump.tracks.len());
+        let row = rows.iter().find(|r| &r[0] == "1").unwrap();
+        assert_eq!(&row[1], "Tribal Battlefield (Original Mix)");
+
+        let mut data = Vec::new();
+        dump.write_playlists_csv(&mut data).unwrap();
+        let mut reader = csv::Reader::from_reader(data.as_slice());
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        let entries: usize = count_entries(&dump.playlists);
+        assert_eq!(rows.len(), entries);
+        assert!(rows.iter().any(|r| &r[1] == "Old House"));
+    }
+
+    fn count_entries(playlists: &[PlaylistRecord]) -> usize {
+        playlists
+            .iter()
+            .map(|p| p.track_ids.len() + count_entries(&p.children))
+            .sum()
+    }
+}
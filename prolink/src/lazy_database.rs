@@ -0,0 +1,369 @@
+use num_traits::FromPrimitive;
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use crate::{
+    database::{Database, DatabaseError, RawAlbum, RawTrack, Table, TableType},
+    Result, TrackMetadata,
+};
+
+// Where to continue reading a table.  Tables are chains of pages so they can
+// only be read front to back.
+#[derive(Debug)]
+struct TableCursor {
+    next_page: Option<u32>,
+    last_page: u32,
+    visited: HashSet<u32>,
+}
+
+// A database reader that only fetches and decodes the pages it needs to
+// answer a lookup.  Decoded rows are kept in an in-memory `Database`, so
+// repeated lookups don't touch the reader.
+pub struct LazyDatabase<R> {
+    r: R,
+    page_size: usize,
+    tables: HashMap<TableType, TableCursor>,
+    cache: Database,
+    pages_read: usize,
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> LazyDatabase<R> {
+    // Reads the file header of `r`.  No table pages are read until they are
+    // needed.
+    pub async fn open(mut r: R) -> Result<LazyDatabase<R>> {
+        let (page_size, table_pointers) = Database::read_table_pointers(&mut r).await?;
+
+        let mut tables = HashMap::new();
+        for (raw_table_type, table_ptr) in table_pointers {
+            if let Some(t) = FromPrimitive::from_u32(raw_table_type) {
+                tables.insert(
+                    t,
+                    TableCursor {
+                        next_page: Some(table_ptr.first_page),
+                        last_page: table_ptr.last_page,
+                        visited: HashSet::new(),
+                    },
+                );
+            }
+        }
+
+        Ok(LazyDatabase {
+            r,
+            page_size,
+            tables,
+            cache: Database::default(),
+            pages_read: 0,
+        })
+    }
+
+    // The rows decoded so far.
+    pub fn cached(&self) -> &Database {
+        &self.cache
+    }
+
+    // Reads the My Tags from exportExt.pdb.  The file is small so it is read
+    // in full.
+    pub async fn parse_ext<E: AsyncRead + AsyncSeek + Unpin>(&mut self, r: &mut E) -> Result<()> {
+        self.cache.parse_ext(r).await
+    }
+
+    pub async fn track(&mut self, id: u32) -> Result<Option<&RawTrack>> {
+        self.load_until(TableType::Tracks, |db| db.tracks.contains_key(&id))
+            .await?;
+        Ok(self.cache.tracks.get(&id))
+    }
+
+    pub async fn artist(&mut self, id: u32) -> Result<Option<&String>> {
+        self.load_until(TableType::Artists, |db| db.artists.contains_key(&id))
+            .await?;
+        Ok(self.cache.artists.get(&id))
+    }
+
+    pub async fn album(&mut self, id: u32) -> Result<Option<&RawAlbum>> {
+        self.load_until(TableType::Albums, |db| db.albums.contains_key(&id))
+            .await?;
+        Ok(self.cache.albums.get(&id))
+    }
+
+    pub async fn genre(&mut self, id: u32) -> Result<Option<&String>> {
+        self.load_until(TableType::Generes, |db| db.generes.contains_key(&id))
+            .await?;
+        Ok(self.cache.generes.get(&id))
+    }
+
+    pub async fn key(&mut self, id: u32) -> Result<Option<&String>> {
+        self.load_until(TableType::Keys, |db| db.keys.contains_key(&id))
+            .await?;
+        Ok(self.cache.keys.get(&id))
+    }
+
+    pub async fn label(&mut self, id: u32) -> Result<Option<&String>> {
+        self.load_until(TableType::Labels, |db| db.labels.contains_key(&id))
+            .await?;
+        Ok(self.cache.labels.get(&id))
+    }
+
+    pub async fn color(&mut self, id: u32) -> Result<Option<&String>> {
+        self.load_until(TableType::Colors, |db| db.colors.contains_key(&id))
+            .await?;
+        Ok(self.cache.colors.get(&id))
+    }
+
+    pub async fn artwork(&mut self, id: u32) -> Result<Option<&String>> {
+        self.load_until(TableType::Artwork, |db| db.artwork.contains_key(&id))
+            .await?;
+        Ok(self.cache.artwork.get(&id))
+    }
+
+    // Looks up track `id` and the rows it references and resolves them into
+    // `TrackMetadata`.  An ID of 0 means the track has no such row, so it is
+    // not looked up as that would read the whole table.
+    pub async fn track_metadata(&mut self, id: u32) -> Result<Option<TrackMetadata>> {
+        let (artist_ids, album_id, genre_id, key_id, label_id, color_id) =
+            match self.track(id).await? {
+                Some(t) => (
+                    [
+                        t.artist_id,
+                        t.composer_id,
+                        t.original_artist_id,
+                        t.remixer_id,
+                    ],
+                    t.album_id,
+                    t.genre_id,
+                    t.key_id,
+                    t.label_id,
+                    t.color_id as u32,
+                ),
+                None => return Ok(None),
+            };
+
+        let mut artist_ids = artist_ids.to_vec();
+        if album_id != 0 {
+            if let Some(album) = self.album(album_id).await? {
+                artist_ids.push(album.artist_id);
+            }
+        }
+        for artist_id in artist_ids {
+            if artist_id != 0 {
+                self.artist(artist_id).await?;
+            }
+        }
+        if genre_id != 0 {
+            self.genre(genre_id).await?;
+        }
+        if key_id != 0 {
+            self.key(key_id).await?;
+        }
+        if label_id != 0 {
+            self.label(label_id).await?;
+        }
+        if color_id != 0 {
+            self.color(color_id).await?;
+        }
+
+        let track = &self.cache.tracks[&id];
+        Ok(Some(TrackMetadata::from_track(&self.cache, track)))
+    }
+
+    // Reads pages of `table_type` until `found` returns true or the table is
+    // exhausted.
+    async fn load_until<F: Fn(&Database) -> bool>(
+        &mut self,
+        table_type: TableType,
+        found: F,
+    ) -> Result<()> {
+        while !found(&self.cache) {
+            if !self.load_next_page(&table_type).await? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false if there are no more pages in the table.
+    async fn load_next_page(&mut self, table_type: &TableType) -> Result<bool> {
+        let cursor = match self.tables.get_mut(table_type) {
+            Some(cursor) => cursor,
+            None => return Ok(false),
+        };
+        let page = match cursor.next_page {
+            Some(page) => page,
+            None => return Ok(false),
+        };
+        if cursor.visited.contains(&page) {
+            return Err(DatabaseError::PageLoop(page).into());
+        }
+
+        let mut page_data = vec![0; self.page_size];
+        self.r
+            .seek(SeekFrom::Start(page as u64 * self.page_size as u64))
+            .await?;
+        self.r.read_exact(&mut page_data).await?;
+        self.pages_read += 1;
+
+        let next_page = self
+            .cache
+            .parse_page(&Table::Export(*table_type), &page_data)?;
+
+        // Only pages that were read count as visited, so a failed read can
+        // be retried.
+        cursor.visited.insert(page);
+        cursor.next_page = if page == cursor.last_page {
+            None
+        } else {
+            Some(next_page)
+        };
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{RawTag, RawTagTrack};
+    use crate::test_util::{load_test_database, TEST_DATABASE};
+    use std::{
+        io::{self, Cursor},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::ReadBuf;
+
+    // Fails the first read that starts at `fail_at`.
+    struct FlakyReader {
+        inner: Cursor<Vec<u8>>,
+        fail_at: Option<u64>,
+    }
+
+    impl AsyncRead for FlakyReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.fail_at == Some(self.inner.position()) {
+                self.fail_at = None;
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncSeek for FlakyReader {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+
+        fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+
+    async fn open_test_database() -> LazyDatabase<tokio::io::BufReader<tokio::fs::File>> {
+        let reader = tokio::fs::File::open(TEST_DATABASE).await.unwrap();
+        LazyDatabase::open(tokio::io::BufReader::new(reader))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lazy_track() {
+        let mut db = open_test_database().await;
+        assert_eq!(db.pages_read, 0);
+
+        let track = db.track(1).await.unwrap().unwrap();
+        assert_eq!(track.title, "Tribal Battlefield (Original Mix)");
+        // The table's header page and its first data page.
+        let pages_read = db.pages_read;
+        assert_eq!(pages_read, 2);
+        assert!(db.cached().artists.is_empty());
+
+        // Cached rows don't read any more pages.
+        db.track(1).await.unwrap().unwrap();
+        assert_eq!(db.pages_read, pages_read);
+
+        assert!(db.track(0xffff_ffff).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lazy_metadata_matches_full_parse() {
+        let mut full = load_test_database().await;
+        let mut tags = Database::default();
+        tags.tags.insert(
+            1,
+            RawTag {
+                id: 1,
+                category_id: 0,
+                category_pos: 0,
+                is_category: true,
+                name: "Energy".to_string(),
+            },
+        );
+        tags.tags.insert(
+            2,
+            RawTag {
+                id: 2,
+                category_id: 1,
+                category_pos: 0,
+                is_category: false,
+                name: "Peak".to_string(),
+            },
+        );
+        tags.tag_tracks.insert(
+            43,
+            vec![RawTagTrack {
+                track_id: 43,
+                tag_id: 2,
+            }],
+        );
+        let mut ext = Vec::new();
+        tags.write_ext(&mut ext).await.unwrap();
+        full.parse_ext(&mut Cursor::new(ext.clone())).await.unwrap();
+
+        let mut lazy = open_test_database().await;
+        lazy.parse_ext(&mut Cursor::new(ext)).await.unwrap();
+        for id in [1, 43, 237] {
+            let expected = TrackMetadata::from_track(&full, &full.tracks[&id]);
+            let metadata = lazy.track_metadata(id).await.unwrap().unwrap();
+            assert_eq!(metadata, expected);
+        }
+        assert_eq!(
+            lazy.track_metadata(43).await.unwrap().unwrap().tags,
+            ["Peak"]
+        );
+        assert!(lazy.track_metadata(0xffff_ffff).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lazy_metadata_skips_missing_ids() {
+        // Track 2 has no album, genre, label or color.
+        let mut lazy = open_test_database().await;
+        let metadata = lazy.track_metadata(2).await.unwrap().unwrap();
+        assert_eq!(metadata.genre, "");
+
+        let cached = lazy.cached();
+        assert!(cached.albums.is_empty());
+        assert!(cached.generes.is_empty());
+        assert!(cached.labels.is_empty());
+        assert!(cached.colors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lazy_retry_after_read_error() {
+        // The track table's first data page is page 2.
+        let data = std::fs::read(TEST_DATABASE).unwrap();
+        let page_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as u64;
+        let reader = FlakyReader {
+            inner: Cursor::new(data),
+            fail_at: Some(2 * page_size),
+        };
+        let mut db = LazyDatabase::open(reader).await.unwrap();
+
+        assert!(db.track(1).await.is_err());
+        let track = db.track(1).await.unwrap().unwrap();
+        assert_eq!(track.title, "Tribal Battlefield (Original Mix)");
+    }
+}
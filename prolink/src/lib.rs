@@ -7,12 +7,19 @@ use tokio::{
     time::Instant,
 };
 
-mod analysis;
-mod database;
+pub mod analysis;
+pub mod database;
+mod database_writer;
+pub mod dump;
+pub mod lazy_database;
+pub mod library;
 pub mod message;
+#[cfg(test)]
+mod test_util;
 //mod metadata;
 mod proto;
 mod tasks;
+pub mod waveform;
 
 use tasks::{
     beat::BeatTask, membership::MembershipTask, metadata::MetadataTask, status::StatusTask,
@@ -65,6 +72,18 @@ pub enum ProlinkError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error(transparent)]
+    Database(#[from] database::DatabaseError),
+
+    #[error(transparent)]
+    Nfs(#[from] prolink_nfs::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+
     #[error(transparent)]
     SystemTime(#[from] std::time::SystemTimeError),
 
@@ -0,0 +1,320 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::HashMap,
+    hash::Hash,
+};
+
+use crate::database::{Database, RawTrack};
+
+// Orders for `Library::tracks`.  Ties are broken by title.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SortOrder {
+    Title,
+    Artist,
+    Album,
+    Tempo,
+    Rating,
+    Year,
+    DateAdded,
+}
+
+// A query index over a `Database`.  The index only holds track IDs and
+// borrows everything else from the database.
+pub struct Library<'a> {
+    db: &'a Database,
+    // All track IDs ordered by title.
+    by_title: Vec<(String, u32)>,
+    // Track IDs ordered by tempo.
+    by_tempo: Vec<(u32, u32)>,
+    by_artist: HashMap<u32, Vec<u32>>,
+    by_album: HashMap<u32, Vec<u32>>,
+    by_genre: HashMap<u32, Vec<u32>>,
+    by_key: HashMap<u32, Vec<u32>>,
+    by_label: HashMap<u32, Vec<u32>>,
+    by_rating: HashMap<u8, Vec<u32>>,
+    by_color: HashMap<u8, Vec<u32>>,
+    by_year: HashMap<u16, Vec<u32>>,
+}
+
+impl<'a> Library<'a> {
+    pub fn new(db: &'a Database) -> Library<'a> {
+        let mut by_title: Vec<(String, u32)> = db
+            .tracks
+            .values()
+            .map(|t| (t.title.to_lowercase(), t.id))
+            .collect();
+        by_title.sort();
+
+        let mut by_tempo: Vec<(u32, u32)> = db.tracks.values().map(|t| (t.tempo, t.id)).collect();
+        by_tempo.sort();
+
+        let mut library = Library {
+            db,
+            by_title,
+            by_tempo,
+            by_artist: HashMap::new(),
+            by_album: HashMap::new(),
+            by_genre: HashMap::new(),
+            by_key: HashMap::new(),
+            by_label: HashMap::new(),
+            by_rating: HashMap::new(),
+            by_color: HashMap::new(),
+            by_year: HashMap::new(),
+        };
+
+        // Walk the tracks in title order so every bucket is sorted by title.
+        for (_, id) in &library.by_title {
+            let track = &db.tracks[id];
+            insert(&mut library.by_artist, track.artist_id, *id);
+            insert(&mut library.by_album, track.album_id, *id);
+            insert(&mut library.by_genre, track.genre_id, *id);
+            insert(&mut library.by_key, track.key_id, *id);
+            insert(&mut library.by_label, track.label_id, *id);
+            insert(&mut library.by_rating, track.rating, *id);
+            insert(&mut library.by_color, track.color_id, *id);
+            insert(&mut library.by_year, track.year, *id);
+        }
+
+        library
+    }
+
+    pub fn database(&self) -> &'a Database {
+        self.db
+    }
+
+    // Returns all tracks in `order`.
+    pub fn tracks(&self, order: SortOrder) -> Vec<&'a RawTrack> {
+        let mut tracks = self.resolve(self.by_title.iter().map(|(_, id)| id));
+        let db = self.db;
+        let name = |names: &'a HashMap<u32, String>, id: u32| -> String {
+            names.get(&id).map(|n| n.to_lowercase()).unwrap_or_default()
+        };
+        let album = |id: u32| -> String {
+            db.albums
+                .get(&id)
+                .map(|a| a.name.to_lowercase())
+                .unwrap_or_default()
+        };
+
+        // The sorts are stable so tracks with equal keys stay in title order.
+        match order {
+            SortOrder::Title => {}
+            SortOrder::Artist => tracks.sort_by_cached_key(|t| name(&db.artists, t.artist_id)),
+            SortOrder::Album => tracks.sort_by_cached_key(|t| album(t.album_id)),
+            SortOrder::Tempo => tracks.sort_by_key(|t| t.tempo),
+            SortOrder::Rating => tracks.sort_by_key(|t| Reverse(t.rating)),
+            SortOrder::Year => tracks.sort_by_key(|t| t.year),
+            SortOrder::DateAdded => tracks.sort_by(|a, b| b.date_added.cmp(&a.date_added)),
+        }
+        tracks
+    }
+
+    pub fn tracks_by_artist(&self, artist_id: u32) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_artist, &artist_id)
+    }
+
+    pub fn tracks_by_album(&self, album_id: u32) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_album, &album_id)
+    }
+
+    pub fn tracks_by_genre(&self, genre_id: u32) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_genre, &genre_id)
+    }
+
+    pub fn tracks_by_key(&self, key_id: u32) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_key, &key_id)
+    }
+
+    pub fn tracks_by_label(&self, label_id: u32) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_label, &label_id)
+    }
+
+    pub fn tracks_by_rating(&self, rating: u8) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_rating, &rating)
+    }
+
+    pub fn tracks_by_color(&self, color_id: u8) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_color, &color_id)
+    }
+
+    pub fn tracks_by_year(&self, year: u16) -> Vec<&'a RawTrack> {
+        self.bucket(&self.by_year, &year)
+    }
+
+    // Returns the tracks with a tempo between `min` and `max` BPM inclusive,
+    // ordered by tempo.
+    pub fn tracks_by_tempo(&self, min: f32, max: f32) -> Vec<&'a RawTrack> {
+        let min = (min * 100.0).round() as u32;
+        let max = (max * 100.0).round() as u32;
+        let start = self.by_tempo.partition_point(|(tempo, _)| *tempo < min);
+        let end = self.by_tempo.partition_point(|(tempo, _)| *tempo <= max);
+        if start >= end {
+            return Vec::new();
+        }
+        self.resolve(self.by_tempo[start..end].iter().map(|(_, id)| id))
+    }
+
+    // Returns the tracks whose title contains `query`, ignoring case.
+    pub fn search_title(&self, query: &str) -> Vec<&'a RawTrack> {
+        let query = query.to_lowercase();
+        self.resolve(
+            self.by_title
+                .iter()
+                .filter(|(title, _)| title.contains(&query))
+                .map(|(_, id)| id),
+        )
+    }
+
+    // Returns the tracks whose title starts with `prefix`, ignoring case.
+    pub fn search_title_prefix(&self, prefix: &str) -> Vec<&'a RawTrack> {
+        let prefix = prefix.to_lowercase();
+        let start = self
+            .by_title
+            .partition_point(|(title, _)| title.as_str() < prefix.as_str());
+        self.resolve(
+            self.by_title[start..]
+                .iter()
+                .take_while(|(title, _)| title.starts_with(&prefix))
+                .map(|(_, id)| id),
+        )
+    }
+
+    // Returns the IDs and names of the artists that have tracks, ordered by
+    // name.
+    pub fn artists(&self) -> Vec<(u32, &'a str)> {
+        Self::names(&self.db.artists, &self.by_artist)
+    }
+
+    pub fn genres(&self) -> Vec<(u32, &'a str)> {
+        Self::names(&self.db.generes, &self.by_genre)
+    }
+
+    pub fn keys(&self) -> Vec<(u32, &'a str)> {
+        Self::names(&self.db.keys, &self.by_key)
+    }
+
+    pub fn labels(&self) -> Vec<(u32, &'a str)> {
+        Self::names(&self.db.labels, &self.by_label)
+    }
+
+    pub fn albums(&self) -> Vec<(u32, &'a str)> {
+        let mut albums: Vec<(u32, &'a str)> = self
+            .db
+            .albums
+            .values()
+            .filter(|a| self.by_album.contains_key(&a.id))
+            .map(|a| (a.id, a.name.as_str()))
+            .collect();
+        albums.sort_by(|a, b| compare_names(a.1, b.1));
+        albums
+    }
+
+    // Returns the years that have tracks in ascending order.
+    pub fn years(&self) -> Vec<u16> {
+        let mut years: Vec<u16> = self.by_year.keys().cloned().collect();
+        years.sort_unstable();
+        years
+    }
+
+    fn names(
+        names: &'a HashMap<u32, String>,
+        index: &HashMap<u32, Vec<u32>>,
+    ) -> Vec<(u32, &'a str)> {
+        let mut names: Vec<(u32, &'a str)> = names
+            .iter()
+            .filter(|(id, _)| index.contains_key(id))
+            .map(|(id, name)| (*id, name.as_str()))
+            .collect();
+        names.sort_by(|a, b| compare_names(a.1, b.1));
+        names
+    }
+
+    fn bucket<K: Eq + Hash>(&self, index: &HashMap<K, Vec<u32>>, key: &K) -> Vec<&'a RawTrack> {
+        match index.get(key) {
+            Some(ids) => self.resolve(ids.iter()),
+            None => Vec::new(),
+        }
+    }
+
+    fn resolve<'b, I: Iterator<Item = &'b u32>>(&self, ids: I) -> Vec<&'a RawTrack> {
+        let db = self.db;
+        ids.filter_map(|id| db.tracks.get(id)).collect()
+    }
+}
+
+fn insert<K: Eq + Hash>(index: &mut HashMap<K, Vec<u32>>, key: K, id: u32) {
+    index.entry(key).or_default().push(id);
+}
+
+fn compare_names(a: &str, b: &str) -> Ordering {
+    a.to_lowercase()
+        .cmp(&b.to_lowercase())
+        .then_with(|| a.cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::load_test_database;
+
+    #[tokio::test]
+    async fn test_library() {
+        let db = load_test_database().await;
+        let library = Library::new(&db);
+
+        let tracks = library.tracks(SortOrder::Title);
+        assert_eq!(tracks.len(), db.tracks.len());
+        for pair in tracks.windows(2) {
+            assert!(pair[0].title.to_lowercase() <= pair[1].title.to_lowercase());
+        }
+
+        for pair in library.tracks(SortOrder::Tempo).windows(2) {
+            assert!(pair[0].tempo <= pair[1].tempo);
+        }
+
+        let track = &db.tracks[&1];
+        let by_artist = library.tracks_by_artist(track.artist_id);
+        assert!(by_artist.iter().any(|t| t.id == 1));
+        assert!(by_artist.iter().all(|t| t.artist_id == track.artist_id));
+        assert!(library
+            .artists()
+            .iter()
+            .any(|(id, _)| *id == track.artist_id));
+
+        let track = tracks[0];
+        assert!(library
+            .tracks_by_genre(track.genre_id)
+            .iter()
+            .any(|t| t.id == track.id));
+        assert!(library
+            .tracks_by_year(track.year)
+            .iter()
+            .any(|t| t.id == track.id));
+
+        let tempo = track.tempo as f32 / 100.0;
+        let in_range = library.tracks_by_tempo(tempo - 0.5, tempo + 0.5);
+        assert!(in_range.iter().any(|t| t.id == track.id));
+        assert!(in_range
+            .iter()
+            .all(|t| (t.tempo as f32 / 100.0 - tempo).abs() <= 0.5));
+        assert!(library.tracks_by_tempo(tempo + 1.0, tempo).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_title_search() {
+        let db = load_test_database().await;
+        let library = Library::new(&db);
+
+        let found = library.search_title_prefix("TRIBAL");
+        assert!(found.iter().any(|t| t.id == 1));
+        assert!(found
+            .iter()
+            .all(|t| t.title.to_lowercase().starts_with("tribal")));
+
+        let found = library.search_title("battlefield (ORIGINAL");
+        assert!(found.iter().any(|t| t.id == 1));
+        assert!(library.search_title_prefix("battlefield").is_empty());
+
+        assert!(library.search_title("no such track title").is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::analysis::{Analysis, Phrase};
 pub use crate::tasks::metadata::TrackMetadata;
 #[derive(Clone, Debug, PartialEq)]
 pub struct Peer {
@@ -14,6 +15,7 @@ pub struct Track {
     pub rekordbox_id: u32,
     pub metadata: Option<TrackMetadata>,
     pub artwork: Option<Vec<u8>>,
+    pub analysis: Option<Analysis>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -36,4 +38,9 @@ pub enum Message {
     PeerLeft(Peer),
     NewTrack(Track),
     Beat(Beat),
+    PhraseChanged {
+        player: u8,
+        phrase: Phrase,
+        beats_remaining: u16,
+    },
 }
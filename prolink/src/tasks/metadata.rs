@@ -1,15 +1,19 @@
 use anyhow::anyhow;
 use log::{debug, info};
-use prolink_nfs::NfsClient;
+use prolink_nfs::{NfsClient, NfsFile};
 use serde::Serialize;
 use std::{
     collections::HashMap,
-    io::Cursor,
     net::{IpAddr, Ipv4Addr},
 };
 use tokio::sync::{broadcast, mpsc, oneshot};
 
-use crate::{database::Database, Message, PeerEvent, Result};
+use crate::{
+    analysis::Analysis,
+    database::{Database, RawTrack},
+    lazy_database::LazyDatabase,
+    Message, PeerEvent, Result,
+};
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct TrackMetadata {
@@ -40,12 +44,78 @@ pub struct TrackMetadata {
     pub mix_name: String,
     pub comment: String,
     pub title: String,
+    // My Tags assigned to the track, ordered by category.
+    pub tags: Vec<String>,
+}
+
+impl TrackMetadata {
+    // Resolves the IDs of `track` against the other tables of `db`.  Missing
+    // entries resolve to empty strings.
+    pub fn from_track(db: &Database, track: &RawTrack) -> TrackMetadata {
+        let blank = "".to_string();
+        let composer = db.artists.get(&track.composer_id).unwrap_or(&blank);
+        let key = db.keys.get(&track.key_id).unwrap_or(&blank);
+        let original_artist = db.artists.get(&track.original_artist_id).unwrap_or(&blank);
+        let label = db.labels.get(&track.label_id).unwrap_or(&blank);
+        let remixer = db.artists.get(&track.remixer_id).unwrap_or(&blank);
+        let genere = db.generes.get(&track.genre_id).unwrap_or(&blank);
+        let album = db.albums.get(&track.album_id);
+        let (album_name, album_artist) = match album {
+            Some(album) => (
+                &album.name,
+                db.artists.get(&album.artist_id).unwrap_or(&blank),
+            ),
+            None => (&blank, &blank),
+        };
+
+        let artist = db.artists.get(&track.artist_id).unwrap_or(&blank);
+        let color = db.colors.get(&(track.color_id as u32)).unwrap_or(&blank);
+
+        let tempo = track.tempo as f32 / 100.0;
+        let tags = db
+            .track_tags(track.id)
+            .iter()
+            .map(|t| t.name.clone())
+            .collect();
+
+        TrackMetadata {
+            sample_rate: track.sample_rate,
+            composer: composer.clone(),
+            file_size: track.file_size,
+            key: key.clone(),
+            original_artist: original_artist.clone(),
+            label: label.clone(),
+            remixer: remixer.clone(),
+            bitrate: track.bitrate,
+            track_number: track.track_number,
+            tempo,
+            genre: genere.clone(),
+            album_name: album_name.clone(),
+            album_artist: album_artist.clone(),
+            artist: artist.clone(),
+            disc: track.disc,
+            play_count: track.play_count,
+            year: track.year,
+            sample_depth: track.sample_depth,
+            duration: track.duration,
+            color: color.clone(),
+            rating: track.rating,
+            isrc: track.isrc.clone(),
+            date_added: track.date_added.clone(),
+            release_date: track.release_date.clone(),
+            mix_name: track.mix_name.clone(),
+            comment: track.comment.clone(),
+            title: track.title.clone(),
+            tags,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TrackInfo {
     pub metadata: TrackMetadata,
     pub artwork: Option<Vec<u8>>,
+    pub analysis: Option<Analysis>,
 }
 
 #[derive(Debug)]
@@ -61,7 +131,7 @@ pub(crate) struct MetadataTask {
     msg_tx: mpsc::Sender<Message>,
     peer_addrs: HashMap<u8, IpAddr>,
     nfs_clients: HashMap<u8, NfsClient>,
-    databases: HashMap<u8, HashMap<u8, Database>>,
+    databases: HashMap<u8, HashMap<u8, LazyDatabase<NfsFile>>>,
     request_tx: mpsc::Sender<MetadataRequest>,
     request_rx: mpsc::Receiver<MetadataRequest>,
 }
@@ -161,83 +231,62 @@ impl MetadataTask {
                 Self::fetch_database(&mut client, request.slot).await?,
             );
         }
-        let db = dbs.get(&request.slot).unwrap();
+        let db = dbs.get_mut(&request.slot).unwrap();
 
-        let track = db
-            .tracks
-            .get(&request.rekordbox_id)
+        let metadata = db
+            .track_metadata(request.rekordbox_id)
+            .await?
             .ok_or(anyhow!("Can't find track for id {}", &request.rekordbox_id))?;
+        let track = &db.cached().tracks[&request.rekordbox_id];
+        let (artwork_id, analyze_path) = (track.artwork_id, track.analyze_path.clone());
 
-        let blank = "".to_string();
-        let composer = db.artists.get(&track.composer_id).unwrap_or(&blank);
-        let key = db.keys.get(&track.key_id).unwrap_or(&blank);
-        let original_artist = db.artists.get(&track.original_artist_id).unwrap_or(&blank);
-        let label = db.labels.get(&track.label_id).unwrap_or(&blank);
-        let remixer = db.artists.get(&track.remixer_id).unwrap_or(&blank);
-        let genere = db.generes.get(&track.genre_id).unwrap_or(&blank);
-        let album = db.albums.get(&track.album_id);
-        let (album_name, album_artist) = match album {
-            Some(album) => (
-                &album.name,
-                db.artists.get(&album.artist_id).unwrap_or(&blank),
-            ),
-            None => (&blank, &blank),
+        let artwork_path = if artwork_id != 0 {
+            db.artwork(artwork_id).await?.cloned()
+        } else {
+            None
         };
-
-        let artist = db.artists.get(&track.artist_id).unwrap_or(&blank);
-        let color = db.colors.get(&(track.color_id as u32)).unwrap_or(&blank);
-
-        let tempo = track.tempo as f32 / 100.0;
-
-        let artwork = match db.artwork.get(&track.artwork_id) {
+        let artwork = match artwork_path {
             Some(path) => {
-                let path = Self::slot_prefix(request.slot)?.to_owned() + path;
+                let path = Self::slot_prefix(request.slot)?.to_owned() + &path;
                 Self::fetch_artwork(client, &path).await
             }
             None => None,
         };
 
+        let analysis = if analyze_path.is_empty() {
+            None
+        } else {
+            let path = Self::slot_prefix(request.slot)?.to_owned() + &analyze_path;
+            Self::fetch_analysis(client, &path).await
+        };
+
         Ok(TrackInfo {
-            metadata: TrackMetadata {
-                sample_rate: track.sample_rate,
-                composer: composer.clone(),
-                file_size: track.file_size,
-                key: key.clone(),
-                original_artist: original_artist.clone(),
-                label: label.clone(),
-                remixer: remixer.clone(),
-                bitrate: track.bitrate,
-                track_number: track.track_number,
-                tempo,
-                genre: genere.clone(),
-                album_name: album_name.clone(),
-                album_artist: album_artist.clone(),
-                artist: artist.clone(),
-                disc: track.disc,
-                play_count: track.play_count,
-                year: track.year,
-                sample_depth: track.sample_depth,
-                duration: track.duration,
-                color: color.clone(),
-                rating: track.rating,
-                isrc: track.strings[0].clone(),
-                date_added: track.strings[10].clone(),
-                release_date: track.strings[11].clone(),
-                mix_name: track.strings[12].clone(),
-                comment: track.strings[16].clone(),
-                title: track.strings[17].clone(),
-            },
+            metadata,
             artwork,
+            analysis,
         })
     }
 
-    async fn fetch_database(client: &mut NfsClient, slot: u8) -> Result<Database> {
+    // Only the database's header is read up front.  Pages are fetched as
+    // lookups need them, so the first track doesn't wait on the whole file.
+    async fn fetch_database(client: &mut NfsClient, slot: u8) -> Result<LazyDatabase<NfsFile>> {
         let prefix = Self::slot_prefix(slot)?;
         let db_path = prefix.to_owned() + "/PIONEER/rekordbox/export.pdb";
-        let data = client.get_file(&db_path).await?;
-        let mut c = Cursor::new(data);
+        let file = client.open(&db_path).await?;
+
+        let mut db = LazyDatabase::open(file).await?;
+
+        // exportExt.pdb is only written by newer versions of rekordbox.
+        let ext_path = prefix.to_owned() + "/PIONEER/rekordbox/exportExt.pdb";
+        match client.open(&ext_path).await {
+            Ok(mut file) => {
+                if let Err(e) = db.parse_ext(&mut file).await {
+                    info!("Failed to parse ext database at {}: {}", ext_path, e);
+                }
+            }
+            Err(e) => debug!("Failed to fetch ext database at {}: {}", ext_path, e),
+        }
 
-        let db = Database::parse(&mut c).await?;
         Ok(db)
     }
 
@@ -268,6 +317,38 @@ impl MetadataTask {
         }
     }
 
+    // `path` points at the track's `.DAT` analysis file.  The `.EXT` and `.2EX`
+    // files next to it are optional and carry the color waveforms, song
+    // structure and CDJ-3000 data.
+    async fn fetch_analysis(client: &mut NfsClient, path: &str) -> Option<Analysis> {
+        let (base, _) = path.rsplit_once(".")?;
+        let mut analysis = Analysis::new();
+
+        for ext in &["DAT", "EXT", "2EX"] {
+            let path = base.to_owned() + "." + ext;
+            let mut file = match client.open(&path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    debug!("Failed to fetch analysis at {}: {}", path, e);
+                    if *ext == "DAT" {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+
+            // Parse each file on its own so one that fails part way through
+            // doesn't leave half its sections in the result.
+            let mut parsed = Analysis::new();
+            match parsed.parse(&mut file).await {
+                Ok(()) => analysis.merge(parsed),
+                Err(e) => info!("Failed to parse analysis at {}: {}", path, e),
+            }
+        }
+
+        Some(analysis)
+    }
+
     fn slot_prefix(slot: u8) -> Result<&'static str> {
         match slot {
             2 => Ok(&"/B"),
@@ -5,7 +5,10 @@ use tokio::{
     sync::{broadcast, mpsc},
 };
 
-use crate::{message, proto, tasks::metadata::MetadataClient, Message, Peer, PeerEvent, Result};
+use crate::{
+    analysis::SongStructure, message, proto, tasks::metadata::MetadataClient, Message, Peer,
+    PeerEvent, Result,
+};
 
 pub(crate) struct StatusTask {
     socket: UdpSocket,
@@ -14,6 +17,13 @@ pub(crate) struct StatusTask {
     metadata: MetadataClient,
     current_tracks: HashMap<u8, message::Track>,
 
+    // Song structures arrive from the metadata fetch along with the track they
+    // belong to so that stale results for unloaded tracks can be dropped.
+    structure_tx: mpsc::Sender<(message::Track, SongStructure)>,
+    structure_rx: mpsc::Receiver<(message::Track, SongStructure)>,
+    structures: HashMap<u8, SongStructure>,
+    current_phrases: HashMap<u8, u16>,
+
     peers: HashMap<u8, Peer>,
 }
 
@@ -24,14 +34,28 @@ impl StatusTask {
         metadata: MetadataClient,
     ) -> Result<StatusTask> {
         let socket = UdpSocket::bind("0.0.0.0:50002").await?;
-        Ok(StatusTask {
+        Ok(Self::with_socket(socket, peers_rx, msg_tx, metadata))
+    }
+
+    fn with_socket(
+        socket: UdpSocket,
+        peers_rx: broadcast::Receiver<PeerEvent>,
+        msg_tx: mpsc::Sender<Message>,
+        metadata: MetadataClient,
+    ) -> StatusTask {
+        let (structure_tx, structure_rx) = mpsc::channel(16);
+        StatusTask {
             socket,
             peers_rx,
             msg_tx,
             metadata,
             current_tracks: HashMap::new(),
+            structure_tx,
+            structure_rx,
+            structures: HashMap::new(),
+            current_phrases: HashMap::new(),
             peers: HashMap::new(),
-        })
+        }
     }
 
     pub(crate) async fn run(mut self) -> Result<()> {
@@ -50,6 +74,11 @@ impl StatusTask {
                         }
                     }
                 }
+                res = self.structure_rx.recv() => {
+                    if let Some((track, structure)) = res {
+                        self.handle_structure(track, structure);
+                    }
+                }
                 res = self.socket.recv_from(&mut buf) => {
                     if let Ok((len, _src)) = res {
                         let buf = &buf[0..len];
@@ -60,6 +89,13 @@ impl StatusTask {
         }
     }
 
+    // Structures fetched for a track that has since been unloaded are dropped.
+    fn handle_structure(&mut self, track: message::Track, structure: SongStructure) {
+        if self.current_tracks.get(&track.player_device) == Some(&track) {
+            self.structures.insert(track.player_device, structure);
+        }
+    }
+
     async fn handle_buf(&mut self, buf: &[u8]) -> Result<()> {
         match proto::Packet::parse_status(buf) {
             Ok(pkt) => match &pkt {
@@ -96,6 +132,7 @@ impl StatusTask {
             rekordbox_id: pkt.rekordbox_id,
             metadata: None,
             artwork: None,
+            analysis: None,
         };
 
         let new_track =
@@ -106,11 +143,16 @@ impl StatusTask {
             };
 
         if new_track {
+            self.structures.remove(&pkt.device_num);
+            self.current_phrases.remove(&pkt.device_num);
+
             if track.rekordbox_id != 0 {
                 let msg_tx = self.msg_tx.clone();
+                let structure_tx = self.structure_tx.clone();
                 let client = self.metadata.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = Self::fecth_metadata(client, track, msg_tx).await {
+                    if let Err(e) = Self::fecth_metadata(client, track, msg_tx, structure_tx).await
+                    {
                         println!("metadata fetch failed: {}", e);
                     }
                 });
@@ -118,6 +160,34 @@ impl StatusTask {
                 self.msg_tx.send(Message::NewTrack(track)).await?;
             }
         }
+
+        self.update_phrase(pkt).await
+    }
+
+    async fn update_phrase(&mut self, pkt: &proto::PlayerStatusPacket) -> Result<()> {
+        let structure = match self.structures.get(&pkt.device_num) {
+            Some(structure) => structure,
+            None => return Ok(()),
+        };
+
+        match structure.phrase_at(pkt.beat) {
+            Some((phrase, beats_remaining)) => {
+                if self.current_phrases.get(&pkt.device_num) != Some(&phrase.index) {
+                    self.current_phrases.insert(pkt.device_num, phrase.index);
+                    self.msg_tx
+                        .send(Message::PhraseChanged {
+                            player: pkt.device_num,
+                            phrase: phrase.clone(),
+                            beats_remaining,
+                        })
+                        .await?;
+                }
+            }
+            None => {
+                self.current_phrases.remove(&pkt.device_num);
+            }
+        }
+
         Ok(())
     }
 
@@ -125,13 +195,160 @@ impl StatusTask {
         client: MetadataClient,
         mut track: message::Track,
         msg_tx: mpsc::Sender<Message>,
+        structure_tx: mpsc::Sender<(message::Track, SongStructure)>,
     ) -> Result<()> {
+        let loaded = track.clone();
         let info = client
             .lookup(track.track_device, track.track_slot, track.rekordbox_id)
             .await?;
         track.metadata = Some(info.metadata);
         track.artwork = info.artwork;
+        track.analysis = info.analysis;
+
+        if let Some(structure) = track.analysis.as_ref().and_then(|a| a.structure.clone()) {
+            // The status task may have gone away, in which case there is no one
+            // left to track phrases.
+            let _ = structure_tx.send((loaded, structure)).await;
+        }
+
         msg_tx.send(Message::NewTrack(track)).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::{Bank, Mood, Phrase, PhraseId, PhraseType},
+        tasks::metadata::MetadataTask,
+    };
+
+    async fn status_task() -> (StatusTask, mpsc::Receiver<Message>) {
+        let (peers_tx, peers_rx) = broadcast::channel(4);
+        let (msg_tx, msg_rx) = mpsc::channel(256);
+        // Nothing answers metadata requests so fetches fail quietly.
+        let metadata = MetadataTask::new(peers_tx.subscribe(), msg_tx.clone()).client();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut task = StatusTask::with_socket(socket, peers_rx, msg_tx, metadata);
+        task.peers.insert(
+            2,
+            Peer {
+                name: "CDJ-3000".to_string(),
+                device_num: 2,
+                mac_addr: [0; 6],
+                ip_addr: [127, 0, 0, 1],
+                proto_ver: 3,
+                last_seen: tokio::time::Instant::now(),
+            },
+        );
+        (task, msg_rx)
+    }
+
+    fn status(rekordbox_id: u32, beat: u32) -> proto::PlayerStatusPacket {
+        let data = include_bytes!("../test-data/status-3000.bin");
+        let mut pkt = match proto::Packet::parse_status(data).unwrap() {
+            proto::Packet::PlayerStatus(pkt) => pkt,
+            pkt => panic!("unexpected packet {:?}", pkt),
+        };
+        pkt.rekordbox_id = rekordbox_id;
+        pkt.beat = beat;
+        pkt
+    }
+
+    fn loaded_track(pkt: &proto::PlayerStatusPacket) -> message::Track {
+        message::Track {
+            player_device: pkt.device_num,
+            track_device: pkt.track_device,
+            track_slot: pkt.track_slot,
+            track_type: pkt.track_type,
+            rekordbox_id: pkt.rekordbox_id,
+            metadata: None,
+            artwork: None,
+            analysis: None,
+        }
+    }
+
+    // Three 32 beat phrases starting on beat 1.
+    fn structure() -> SongStructure {
+        SongStructure {
+            mood: Mood::Mid,
+            end_beat: 97,
+            bank: Bank::Default,
+            phrases: (0..3)
+                .map(|i| Phrase {
+                    index: i + 1,
+                    beats: vec![1 + 32 * i],
+                    phrase_id: PhraseId {
+                        ty: PhraseType::Verse,
+                        index: 1,
+                    },
+                    fill_beats: 0,
+                })
+                .collect(),
+        }
+    }
+
+    fn phrase_changes(msg_rx: &mut mpsc::Receiver<Message>) -> Vec<(u16, u16)> {
+        let mut changes = Vec::new();
+        while let Ok(msg) = msg_rx.try_recv() {
+            if let Message::PhraseChanged {
+                player,
+                phrase,
+                beats_remaining,
+            } = msg
+            {
+                assert_eq!(player, 2);
+                changes.push((phrase.index, beats_remaining));
+            }
+        }
+        changes
+    }
+
+    #[tokio::test]
+    async fn test_phrase_changes() {
+        let (mut task, mut msg_rx) = status_task().await;
+
+        let pkt = status(5, 0);
+        task.handle_player_status_packet(&pkt).await.unwrap();
+        task.handle_structure(loaded_track(&pkt), structure());
+
+        // One change as each phrase starts, none within a phrase and none
+        // past the end of the last one.
+        for beat in 0..110 {
+            task.handle_player_status_packet(&status(5, beat))
+                .await
+                .unwrap();
+        }
+        assert_eq!(phrase_changes(&mut msg_rx), vec![(1, 32), (2, 32), (3, 32)]);
+
+        // Jumping back into a phrase reports it again.
+        task.handle_player_status_packet(&status(5, 40))
+            .await
+            .unwrap();
+        task.handle_player_status_packet(&status(5, 41))
+            .await
+            .unwrap();
+        assert_eq!(phrase_changes(&mut msg_rx), vec![(2, 25)]);
+    }
+
+    #[tokio::test]
+    async fn test_stale_structure_is_dropped() {
+        let (mut task, mut msg_rx) = status_task().await;
+
+        let first = status(5, 0);
+        task.handle_player_status_packet(&first).await.unwrap();
+        task.handle_player_status_packet(&status(6, 0))
+            .await
+            .unwrap();
+
+        // The structure for the first track arrives after it was replaced.
+        task.handle_structure(loaded_track(&first), structure());
+        for beat in 0..110 {
+            task.handle_player_status_packet(&status(6, beat))
+                .await
+                .unwrap();
+        }
+        assert!(phrase_changes(&mut msg_rx).is_empty());
+    }
+}
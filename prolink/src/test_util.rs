@@ -0,0 +1,10 @@
+// Helpers shared by the tests of the database modules.
+use crate::database::Database;
+
+pub(crate) const TEST_DATABASE: &str = "src/test-data/export.pdb";
+
+pub(crate) async fn load_test_database() -> Database {
+    let reader = tokio::fs::File::open(TEST_DATABASE).await.unwrap();
+    let mut reader = tokio::io::BufReader::new(reader);
+    Database::parse(&mut reader).await.unwrap()
+}
@@ -0,0 +1,452 @@
+use std::convert::TryInto;
+
+#[cfg(feature = "png")]
+use anyhow::anyhow;
+
+use crate::analysis::{
+    ColorDetailColumn, ColorPreviewColumn, Cue, GridBeat, MonochromeColumn, ThreeBandColumn,
+};
+#[cfg(feature = "png")]
+use crate::Result;
+
+pub type Rgba = [u8; 4];
+
+const BACKGROUND: Rgba = [0x00, 0x00, 0x00, 0xff];
+const PLAYHEAD: Rgba = [0xff, 0xff, 0xff, 0xff];
+const BEAT: Rgba = [0xff, 0xff, 0xff, 0xff];
+const DOWNBEAT: Rgba = [0xff, 0x00, 0x00, 0xff];
+const MEMORY_CUE: Rgba = [0xff, 0x00, 0x00, 0xff];
+const HOT_CUE: Rgba = [0x00, 0xff, 0x00, 0xff];
+
+// Blue waveform colors indexed by whiteness.
+const MONOCHROME_COLORS: [Rgba; 8] = [
+    [0x00, 0x68, 0x90, 0xff],
+    [0x00, 0x88, 0xb0, 0xff],
+    [0x00, 0xa8, 0xe8, 0xff],
+    [0x00, 0xb8, 0xd8, 0xff],
+    [0x78, 0xb8, 0xd8, 0xff],
+    [0x88, 0xc0, 0xe8, 0xff],
+    [0x88, 0xc0, 0xe8, 0xff],
+    [0xc8, 0xe0, 0xe8, 0xff],
+];
+
+const THREE_BAND_LOW: Rgba = [0x20, 0x53, 0xd9, 0xff];
+const THREE_BAND_MID: Rgba = [0xf2, 0xaa, 0x3c, 0xff];
+const THREE_BAND_HIGH: Rgba = [0xff, 0xff, 0xff, 0xff];
+
+// Detail waveforms have one column per half frame (150 per second).
+const DETAIL_COLUMNS_PER_SECOND: u64 = 150;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Waveform<'a> {
+    Monochrome(&'a [MonochromeColumn]),
+    TinyPreview(&'a [MonochromeColumn]),
+    ColorPreview(&'a [ColorPreviewColumn]),
+    ColorDetail(&'a [ColorDetailColumn]),
+    ThreeBand(&'a [ThreeBandColumn]),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum View {
+    // The whole track is stretched across the image and drawn up from the
+    // bottom edge.  `duration` is the track length in milliseconds and is used
+    // to place the overlays.
+    Preview { duration: u32 },
+
+    // A scrolling window centered on `center` (in milliseconds) drawn around
+    // the middle of the image, `scale` waveform columns to a pixel.
+    Detail { center: u32, scale: u32 },
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Overlay<'a> {
+    pub playhead: Option<u32>,
+    pub beat_grid: &'a [GridBeat],
+    pub cues: &'a [Cue],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    // RGBA, row major.
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    fn new(width: u32, height: u32) -> Image {
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+        for _ in 0..(width as usize * height as usize) {
+            pixels.extend_from_slice(&BACKGROUND);
+        }
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Rgba {
+        let offset = (y as usize * self.width as usize + x as usize) * 4;
+        self.pixels[offset..(offset + 4)].try_into().unwrap()
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, color: Rgba) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let offset = (y as usize * self.width as usize + x as usize) * 4;
+        self.pixels[offset..(offset + 4)].copy_from_slice(&color);
+    }
+
+    fn vline(&mut self, x: u32, top: u32, bottom: u32, color: Rgba) {
+        for y in top..bottom {
+            self.set_pixel(x, y, color);
+        }
+    }
+
+    #[cfg(feature = "png")]
+    pub fn write_png<W: std::io::Write>(&self, w: W) -> Result<()> {
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder
+            .write_header()
+            .map_err(|e| anyhow!("error writing png header: {}", e))?;
+        writer
+            .write_image_data(&self.pixels)
+            .map_err(|e| anyhow!("error writing png data: {}", e))?;
+        Ok(())
+    }
+}
+
+impl<'a> Waveform<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Waveform::Monochrome(c) | Waveform::TinyPreview(c) => c.len(),
+            Waveform::ColorPreview(c) => c.len(),
+            Waveform::ColorDetail(c) => c.len(),
+            Waveform::ThreeBand(c) => c.len(),
+        }
+    }
+
+    // Returns the layers to draw for the tallest column in `start..end`, back
+    // to front, with heights from 0.0 to 1.0.
+    fn layers(&self, start: usize, end: usize) -> Vec<(Rgba, f32)> {
+        match self {
+            Waveform::Monochrome(c) => Self::monochrome_layers(&c[start..end], 31.0),
+            Waveform::TinyPreview(c) => Self::monochrome_layers(&c[start..end], 15.0),
+            Waveform::ColorPreview(c) => {
+                let column = Self::tallest(&c[start..end], |c| c.red.max(c.green).max(c.blue));
+                let back = column.red.max(column.green).max(column.blue);
+                if back == 0 {
+                    return Vec::new();
+                }
+                let color = |max: u32| {
+                    [
+                        (column.red as u32 * max / back as u32) as u8,
+                        (column.green as u32 * max / back as u32) as u8,
+                        (column.blue as u32 * max / back as u32) as u8,
+                        0xff,
+                    ]
+                };
+                vec![
+                    (color(0xbf), back as f32 / 127.0),
+                    (color(0xff), column.blue as f32 / 127.0),
+                ]
+            }
+            Waveform::ColorDetail(c) => {
+                let column = Self::tallest(&c[start..end], |c| c.height);
+                let scale = |v: u8| (v as u32 * 0xff / 7) as u8;
+                vec![(
+                    [
+                        scale(column.red),
+                        scale(column.green),
+                        scale(column.blue),
+                        0xff,
+                    ],
+                    column.height as f32 / 31.0,
+                )]
+            }
+            Waveform::ThreeBand(c) => {
+                let column = Self::tallest(&c[start..end], |c| c.low.max(c.mid).max(c.high));
+                vec![
+                    (THREE_BAND_LOW, column.low as f32 / 255.0),
+                    (THREE_BAND_MID, column.mid as f32 / 255.0),
+                    (THREE_BAND_HIGH, column.high as f32 / 255.0),
+                ]
+            }
+        }
+    }
+
+    fn monochrome_layers(columns: &[MonochromeColumn], max_height: f32) -> Vec<(Rgba, f32)> {
+        let column = Self::tallest(columns, |c| c.height);
+        vec![(
+            MONOCHROME_COLORS[column.whiteness as usize & 0x7],
+            column.height as f32 / max_height,
+        )]
+    }
+
+    fn tallest<C: Copy, F: Fn(&C) -> u8>(columns: &[C], height: F) -> C {
+        *columns
+            .iter()
+            .max_by_key(|c| height(c))
+            .expect("empty column range")
+    }
+}
+
+// Maps between pixels, waveform columns and track time for a view.
+struct Mapping {
+    width: u32,
+    num_columns: usize,
+    view: View,
+}
+
+impl Mapping {
+    // Column range covered by pixel `x`.  Returns `None` for pixels outside of
+    // the waveform.
+    fn columns(&self, x: u32) -> Option<(usize, usize)> {
+        let (start, end) = match self.view {
+            View::Preview { .. } => {
+                let start = x as usize * self.num_columns / self.width as usize;
+                let end = (x as usize + 1) * self.num_columns / self.width as usize;
+                (start, end.max(start + 1))
+            }
+            View::Detail { scale, .. } => {
+                let scale = scale.max(1) as i64;
+                let first = self.center_column() - (self.width as i64 / 2) * scale;
+                let start = first + x as i64 * scale;
+                if start < 0 {
+                    return None;
+                }
+                (start as usize, start as usize + scale as usize)
+            }
+        };
+
+        if start >= self.num_columns {
+            None
+        } else {
+            Some((start, end.min(self.num_columns)))
+        }
+    }
+
+    fn center_column(&self) -> i64 {
+        match self.view {
+            View::Preview { .. } => 0,
+            View::Detail { center, .. } => {
+                (center as u64 * DETAIL_COLUMNS_PER_SECOND / 1000) as i64
+            }
+        }
+    }
+
+    // Pixel column for a time in milliseconds if it is visible.
+    fn x(&self, time: u32) -> Option<u32> {
+        let x = match self.view {
+            View::Preview { duration } => {
+                if duration == 0 {
+                    return None;
+                }
+                time as i64 * self.width as i64 / duration as i64
+            }
+            View::Detail { scale, .. } => {
+                let column = (time as u64 * DETAIL_COLUMNS_PER_SECOND / 1000) as i64;
+                (column - self.center_column()) / scale.max(1) as i64 + self.width as i64 / 2
+            }
+        };
+
+        if x >= 0 && x < self.width as i64 {
+            Some(x as u32)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn render(waveform: Waveform, view: View, width: u32, height: u32, overlay: &Overlay) -> Image {
+    let mut image = Image::new(width, height);
+    if width == 0 || height == 0 {
+        return image;
+    }
+
+    let mapping = Mapping {
+        width,
+        num_columns: waveform.len(),
+        view,
+    };
+
+    for x in 0..width {
+        let (start, end) = match mapping.columns(x) {
+            Some(range) => range,
+            None => continue,
+        };
+        for (color, level) in waveform.layers(start, end) {
+            let level = level.clamp(0.0, 1.0);
+            match view {
+                View::Preview { .. } => {
+                    let bar = (level * height as f32).round() as u32;
+                    image.vline(x, height - bar, height, color);
+                }
+                View::Detail { .. } => {
+                    let half = (level * height as f32 / 2.0).round() as u32;
+                    let mid = height / 2;
+                    image.vline(x, mid.saturating_sub(half), (mid + half).min(height), color);
+                }
+            }
+        }
+    }
+
+    // Beat ticks are drawn along the top and bottom edges like the players.
+    let tick = (height / 8).max(1);
+    for beat in overlay.beat_grid {
+        if let Some(x) = mapping.x(beat.time) {
+            let color = if beat.beat_number == 1 {
+                DOWNBEAT
+            } else {
+                BEAT
+            };
+            image.vline(x, 0, tick, color);
+            image.vline(x, height - tick, height, color);
+        }
+    }
+
+    for cue in overlay.cues {
+        if let Some(x) = mapping.x(cue.time) {
+            let color = match cue.color {
+                Some([r, g, b]) => [r, g, b, 0xff],
+                None if cue.hot_cue == 0 => MEMORY_CUE,
+                None => HOT_CUE,
+            };
+            image.vline(x, 0, height, color);
+            // Small triangle flag at the top.
+            for row in 0..tick.min(4) {
+                for dx in 0..=(3 - row) {
+                    image.set_pixel(x.saturating_sub(dx), row, color);
+                    image.set_pixel(x + dx, row, color);
+                }
+            }
+        }
+    }
+
+    if let Some(x) = overlay.playhead.and_then(|t| mapping.x(t)) {
+        image.vline(x, 0, height, PLAYHEAD);
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{Analysis, CueType};
+
+    async fn load(path: &str) -> Analysis {
+        let mut analysis = Analysis::new();
+        let reader = tokio::fs::File::open(path).await.unwrap();
+        let mut reader = tokio::io::BufReader::new(reader);
+        analysis.parse(&mut reader).await.unwrap();
+        analysis
+    }
+
+    #[tokio::test]
+    async fn test_render_preview() {
+        let analysis = load("src/test-data/ANLZ0000.DAT").await;
+        let preview = analysis.waveforms.preview.as_ref().unwrap();
+        let image = render(
+            Waveform::Monochrome(preview),
+            View::Preview { duration: 300_000 },
+            400,
+            31,
+            &Overlay {
+                playhead: Some(150_000),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(image.pixels.len(), 400 * 31 * 4);
+
+        // First column is height 11 with whiteness 3.
+        assert_eq!(image.pixel(0, 30), MONOCHROME_COLORS[3]);
+        assert_eq!(image.pixel(0, 20), MONOCHROME_COLORS[3]);
+        assert_eq!(image.pixel(0, 19), BACKGROUND);
+
+        assert_eq!(image.pixel(200, 0), PLAYHEAD);
+        assert_eq!(image.pixel(200, 30), PLAYHEAD);
+    }
+
+    #[tokio::test]
+    async fn test_render_detail() {
+        let analysis = load("src/test-data/ANLZ0000.DAT").await;
+        let ext = load("src/test-data/ANLZ0000.EXT").await;
+        let detail = ext.waveforms.color_detail.as_ref().unwrap();
+        let beat_grid = analysis.beat_grid.as_ref().unwrap();
+
+        // Beat 4 (a downbeat) is at 1904ms.
+        let image = render(
+            Waveform::ColorDetail(detail),
+            View::Detail {
+                center: 1904,
+                scale: 1,
+            },
+            300,
+            64,
+            &Overlay {
+                playhead: None,
+                beat_grid,
+                cues: &[Cue {
+                    hot_cue: 0,
+                    ty: CueType::Point,
+                    time: 2381,
+                    loop_time: None,
+                    comment: String::new(),
+                    color: None,
+                }],
+            },
+        );
+
+        assert_eq!(image.width, 300);
+        assert_eq!(image.height, 64);
+        assert_eq!(image.pixel(150, 0), DOWNBEAT);
+        assert_eq!(image.pixel(150, 63), DOWNBEAT);
+
+        // Memory cue on the next beat, 72 columns to the right.
+        assert_eq!(image.pixel(222, 32), MEMORY_CUE);
+    }
+
+    #[test]
+    fn test_render_three_band() {
+        let columns = [ThreeBandColumn {
+            mid: 0x80,
+            high: 0x40,
+            low: 0xff,
+        }];
+        let image = render(
+            Waveform::ThreeBand(&columns),
+            View::Preview { duration: 0 },
+            2,
+            8,
+            &Overlay::default(),
+        );
+
+        assert_eq!(image.pixel(1, 0), THREE_BAND_LOW);
+        assert_eq!(image.pixel(1, 4), THREE_BAND_MID);
+        assert_eq!(image.pixel(1, 7), THREE_BAND_HIGH);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_write_png() {
+        let image = render(
+            Waveform::TinyPreview(&[MonochromeColumn {
+                height: 15,
+                whiteness: 0,
+            }]),
+            View::Preview { duration: 0 },
+            4,
+            4,
+            &Overlay::default(),
+        );
+        let mut data = Vec::new();
+        image.write_png(&mut data).unwrap();
+        assert_eq!(&data[1..4], b"PNG");
+    }
+}